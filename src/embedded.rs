@@ -1,16 +1,21 @@
-use crate::{NetworkError, errors::FSError, shared::*};
+use crate::{NetworkError, errors::FSError, fat, framing, shared::*};
 use alloc::{
     boxed::Box,
     rc::Rc,
     string::{String, ToString},
     vec::Vec,
 };
-use core::{cell::RefCell, marker::PhantomData, str};
+use core::{
+    cell::{Cell, RefCell},
+    marker::PhantomData,
+    net::IpAddr,
+    str,
+};
 use embedded_hal_bus::spi::ExclusiveDevice;
 use embedded_io::Read;
 use embedded_sdmmc::{
-    LfnBuffer, Mode, RawDirectory, RawFile, RawVolume, SdCard, ShortFileName, VolumeIdx,
-    VolumeManager, filesystem::ToShortFileName,
+    Block, BlockIdx, DirEntry, LfnBuffer, Mode, RawDirectory, RawFile, RawVolume, SdCard,
+    ShortFileName, VolumeIdx, VolumeManager, filesystem::ToShortFileName,
 };
 use esp_hal::{
     Blocking, delay::Delay, gpio::Output, rng::Rng, spi::master::Spi, uart::Uart,
@@ -21,7 +26,7 @@ use firefly_types::Encode;
 type IoUart = Uart<'static, Blocking>;
 type SdSpi = ExclusiveDevice<Spi<'static, Blocking>, Output<'static>, Delay>;
 type SD = SdCard<SdSpi, Delay>;
-type VM = VolumeManager<SD, FakeTimesource, 48, 12, 1>;
+type VM = VolumeManager<SD, Clock, 48, 12, 1>;
 
 pub struct DeviceImpl<'a> {
     delay: Delay,
@@ -29,8 +34,13 @@ pub struct DeviceImpl<'a> {
     vm: Rc<RefCell<VM>>,
     io_uart: IoUart,
     usb_serial: UsbSerialJtag<'static, Blocking>,
+    /// Reassembles the serial messages from the bytes received so far.
+    serial_frames: framing::Deframer,
+    /// Encoded serial messages not yet written into the USB buffer.
+    serial_out: framing::FrameQueue,
     addr: Addr,
     rng: Rng,
+    clock: Clock,
     _life: &'a PhantomData<()>,
 }
 
@@ -42,7 +52,8 @@ impl DeviceImpl<'_> {
         rng: Rng,
     ) -> Result<Self, NetworkError> {
         let sdcard = SdCard::new(sd_spi, Delay::new());
-        let volume_manager: VM = VolumeManager::new_with_limits(sdcard, FakeTimesource {}, 5000);
+        let clock = Clock::new();
+        let volume_manager: VM = VolumeManager::new_with_limits(sdcard, clock.clone(), 5000);
         let Ok(volume) = volume_manager.open_volume(VolumeIdx(0)) else {
             return Err(NetworkError::Error("failed to open SD card volume 0"));
         };
@@ -54,8 +65,11 @@ impl DeviceImpl<'_> {
             vm: Rc::new(RefCell::new(volume_manager)),
             io_uart,
             usb_serial,
+            serial_frames: framing::Deframer::new(framing::SERIAL_MAX_SIZE),
+            serial_out: framing::FrameQueue::new(framing::SERIAL_QUEUE_SIZE),
             addr: Default::default(),
             rng,
+            clock,
             _life: &PhantomData,
         };
 
@@ -73,7 +87,28 @@ impl DeviceImpl<'_> {
     fn log(&mut self, msg: &str) {
         let msg = firefly_types::serial::Response::Log(msg.to_string());
         let raw = msg.encode_vec().unwrap();
-        send_to_serial(&mut self.usb_serial, &raw);
+        self.serial_out.push(&framing::encode(&raw));
+        self.flush_serial();
+    }
+
+    /// Write into the USB buffer as many queued serial messages as it fits.
+    ///
+    /// Non-blocking writes ensure that we won't block forever
+    /// if there is no client connected listening for messages.
+    /// The bytes that don't fit stay in the queue until the next flush.
+    fn flush_serial(&mut self) {
+        let usb = &mut self.usb_serial;
+        self.serial_out.flush(|bytes| {
+            let mut written = 0;
+            for byte in bytes {
+                if usb.write_byte_nb(*byte).is_err() {
+                    break;
+                }
+                written += 1;
+            }
+            written
+        });
+        _ = usb.flush_tx_nb();
     }
 
     pub fn alloc_psram(&self, size: usize) -> Vec<u8, esp_alloc::ExternalMemory> {
@@ -86,9 +121,17 @@ impl DeviceImpl<'_> {
 /// If the name is a valid FAT-16 short name, use that name directly.
 /// Otherwise, iterate through all items in the directory, find an entry
 /// with the given long name, get its short name, and use that to open the directory.
-fn open_dir(manager: &mut VM, dir: RawDirectory, name: &str) -> Result<RawDirectory, FSError> {
+///
+/// Also returns the position of the directory entry in the parent directory.
+fn open_dir(
+    manager: &mut VM,
+    dir: RawDirectory,
+    name: &str,
+) -> Result<(RawDirectory, fat::EntryPos), FSError> {
     let short_name = get_short_name(manager, dir, name)?;
-    Ok(manager.open_dir(dir, short_name)?)
+    let entry = manager.find_directory_entry(dir, &short_name)?;
+    let subdir = manager.open_dir(dir, short_name)?;
+    Ok((subdir, entry_pos(&entry)))
 }
 
 fn get_short_name(manager: &VM, dir: RawDirectory, name: &str) -> Result<ShortFileName, FSError> {
@@ -138,15 +181,23 @@ impl<'a> Device for DeviceImpl<'a> {
     fn now(&self) -> Instant {
         let now = esp_hal::time::Instant::now();
         Instant {
-            us: now.duration_since_epoch().as_micros() as u32,
+            us: now.duration_since_epoch().as_micros(),
         }
     }
 
     fn delay(&self, d: Duration) {
-        let d = esp_hal::time::Duration::from_micros(d.us() as u64);
+        let d = esp_hal::time::Duration::from_micros(d.us());
         self.delay.delay(d);
     }
 
+    fn now_utc(&self) -> Option<DateTime> {
+        self.clock.now_utc()
+    }
+
+    fn set_utc(&mut self, now: DateTime) {
+        self.clock.set_utc(now);
+    }
+
     fn read_input(&mut self) -> Option<InputState> {
         use firefly_types::spi::*;
         let req = Request::ReadInput;
@@ -188,13 +239,17 @@ impl<'a> Device for DeviceImpl<'a> {
     fn open_dir(&mut self, path: &[&str]) -> Result<DirImpl, FSError> {
         let mut manager = self.vm.borrow_mut();
         let mut dir = manager.open_root_dir(self.volume)?;
+        let mut entry = None;
         for part in path {
             let open_res = open_dir(&mut manager, dir, part);
             _ = manager.close_dir(dir);
-            dir = open_res?;
+            let (subdir, pos) = open_res?;
+            dir = subdir;
+            entry = Some(pos);
         }
         Ok(DirImpl {
             dir,
+            entry,
             vm: self.vm.clone(),
         })
     }
@@ -207,6 +262,10 @@ impl<'a> Device for DeviceImpl<'a> {
         &mut []
     }
 
+    fn read_audio(&mut self) -> &[i16] {
+        &[]
+    }
+
     fn get_battery_status(&mut self) -> Option<BatteryStatus> {
         Some(BatteryStatus {
             voltage: 50,
@@ -219,11 +278,16 @@ impl<'a> Device for DeviceImpl<'a> {
 pub struct DirImpl {
     vm: Rc<RefCell<VM>>,
     dir: RawDirectory,
+    /// The position of the directory entry in the parent directory.
+    ///
+    /// None for the root directory.
+    entry: Option<fat::EntryPos>,
 }
 
 impl Dir for DirImpl {
     type Read = FileR;
     type Write = FileW;
+    type ReadWrite = FileW;
 
     fn open_file(&mut self, name: &str) -> Result<Self::Read, FSError> {
         let manager = &self.vm.borrow();
@@ -252,12 +316,32 @@ impl Dir for DirImpl {
         })
     }
 
+    fn open_file_rw(&mut self, name: &str) -> Result<Self::ReadWrite, FSError> {
+        let manager = &self.vm.borrow();
+        // There is no mode for opening an existing file for writing
+        // without truncating it, so we open it in append mode
+        // and then move the cursor to the beginning.
+        let file = open_file(manager, self.dir, name, Mode::ReadWriteAppend)?;
+        if let Err(err) = manager.file_seek_from_start(file, 0) {
+            _ = manager.close_file(file);
+            return Err(err.into());
+        }
+        Ok(FileW {
+            vm: Rc::clone(&self.vm),
+            file,
+        })
+    }
+
     fn get_file_size(&mut self, name: &str) -> Result<u32, FSError> {
+        let meta = self.metadata(name)?;
+        Ok(meta.size)
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Metadata, FSError> {
         let manager = &self.vm.borrow();
-        let file = open_file(manager, self.dir, name, Mode::ReadOnly)?;
-        let size = manager.file_length(file)?;
-        _ = manager.close_file(file);
-        Ok(size)
+        let short_name = get_short_name(manager, self.dir, name)?;
+        let entry = manager.find_directory_entry(self.dir, short_name)?;
+        Ok(convert_metadata(&entry))
     }
 
     fn remove_file(&mut self, name: &str) -> Result<(), FSError> {
@@ -273,6 +357,25 @@ impl Dir for DirImpl {
         Ok(())
     }
 
+    fn rename_file(&mut self, from: &str, to: &str) -> Result<(), FSError> {
+        let manager = &self.vm.borrow();
+        let from_name = get_short_name(manager, self.dir, from)?;
+        let src = manager.find_directory_entry(self.dir, &from_name)?;
+        if src.attributes.is_directory() {
+            return Err(FSError::OpenedDirAsFile);
+        }
+        // embedded-sdmmc doesn't provide a way to rename a directory entry.
+        // So, we make sure the target file exists (creating an empty one if needed)
+        // and then move the content of the old file into its directory entry.
+        let to_name = get_short_name(manager, self.dir, to)?;
+        let file = manager.open_file_in_dir(self.dir, &to_name, Mode::ReadWriteCreateOrAppend)?;
+        manager.close_file(file)?;
+        let dst = manager.find_directory_entry(self.dir, &to_name)?;
+        with_fat(manager, |vol, dev| {
+            vol.replace(dev, self.entry, entry_pos(&src), entry_pos(&dst))
+        })
+    }
+
     fn create_dir(&mut self, name: &str) -> Result<(), FSError> {
         let manager = &self.vm.borrow();
         let dir = self.dir.to_directory(manager);
@@ -281,26 +384,29 @@ impl Dir for DirImpl {
     }
 
     fn remove_dir(self) -> Result<(), FSError> {
-        let manager = &self.vm.borrow();
-        let dir = self.dir.to_directory(manager);
-        let mut names = Vec::new();
-        dir.iterate_dir(|entry| {
-            let name_str = entry.name.to_string();
-            if &name_str != "." && &name_str != ".." {
-                names.push(entry.name.clone());
-            }
-        })?;
-        for name in names {
-            dir.delete_file_in_dir(&name)?;
-        }
-        // TODO: Add `delete_file_in_dir` when embedded-sdmmc 0.10.0 is released.
-        //      https://github.com/rust-embedded-community/embedded-sdmmc-rs/pull/210
-        Ok(())
+        let parent = remove_dir_contents(&self.vm.borrow(), self.dir)?;
+        // The root directory can only be emptied.
+        let Some(entry) = self.entry else {
+            return Ok(());
+        };
+        // The directory is closed when dropped and it must be closed
+        // before its entry is removed.
+        let vm = Rc::clone(&self.vm);
+        drop(self);
+        let manager = &vm.borrow();
+        with_fat(manager, |vol, dev| vol.remove(dev, parent, entry))
     }
 
     fn iter_dir<F>(&mut self, mut f: F) -> Result<(), FSError>
     where
         F: FnMut(crate::EntryKind, &[u8]),
+    {
+        self.iter_dir_meta(|meta, name| f(meta.kind, name))
+    }
+
+    fn iter_dir_meta<F>(&mut self, mut f: F) -> Result<(), FSError>
+    where
+        F: FnMut(&Metadata, &[u8]),
     {
         let manager = &self.vm.borrow();
         let mut buf = [0u8; 64];
@@ -314,17 +420,118 @@ impl Dir for DirImpl {
                 Some(long_name) => long_name.trim_ascii().as_bytes(),
                 None => base_name,
             };
-            let kind = if entry.attributes.is_directory() {
-                EntryKind::Dir
-            } else {
-                EntryKind::File
-            };
-            f(kind, name);
+            f(&convert_metadata(entry), name);
         })?;
         Ok(())
     }
 }
 
+fn convert_metadata(entry: &DirEntry) -> Metadata {
+    let kind = if entry.attributes.is_directory() {
+        EntryKind::Dir
+    } else {
+        EntryKind::File
+    };
+    Metadata {
+        kind,
+        size: entry.size,
+        modified: convert_timestamp(&entry.mtime),
+    }
+}
+
+/// Convert FAT timestamp into [DateTime].
+///
+/// The FAT zero date is read by embedded-sdmmc as 1980-01-01, the FAT epoch.
+/// The same date is written when the clock was never synced
+/// (see the `TimeSource` implementation for [Clock]). In both cases, the time is unknown.
+fn convert_timestamp(ts: &embedded_sdmmc::Timestamp) -> Option<DateTime> {
+    if ts.year_since_1970 <= 10 && ts.zero_indexed_month == 0 && ts.zero_indexed_day == 0 {
+        return None;
+    }
+    Some(DateTime {
+        year: 1970 + u16::from(ts.year_since_1970),
+        month: ts.zero_indexed_month + 1,
+        day: ts.zero_indexed_day + 1,
+        hour: ts.hours,
+        minute: ts.minutes,
+        second: ts.seconds,
+    })
+}
+
+/// Recursively delete all files and subdirectories in the directory.
+///
+/// embedded-sdmmc 0.9 refuses to delete directories and doesn't free
+/// the clusters of deleted files, so the entries are deleted using [fat].
+/// Returns the position of the ".." entry, None for the root directory.
+fn remove_dir_contents(manager: &VM, dir: RawDirectory) -> Result<Option<fat::EntryPos>, FSError> {
+    let mut entries = Vec::new();
+    // The "." entry points to the directory itself and ".." to its parent.
+    // The root directory has neither.
+    let mut this = None;
+    let mut parent = None;
+    manager.iterate_dir(dir, |entry| {
+        let base_name = entry.name.base_name();
+        if base_name == b"." {
+            this = Some(entry_pos(entry));
+        } else if base_name == b".." {
+            parent = Some(entry_pos(entry));
+        } else if !entry.attributes.is_volume() {
+            entries.push(entry.clone());
+        }
+    })?;
+    for entry in entries {
+        let pos = entry_pos(&entry);
+        if entry.attributes.is_directory() {
+            let subdir = manager.open_dir(dir, &entry.name)?;
+            let res = remove_dir_contents(manager, subdir);
+            _ = manager.close_dir(subdir);
+            res?;
+        }
+        with_fat(manager, |vol, dev| vol.remove(dev, this, pos))?;
+    }
+    Ok(parent)
+}
+
+fn entry_pos(entry: &DirEntry) -> fat::EntryPos {
+    fat::EntryPos {
+        block: entry.entry_block.0,
+        offset: entry.entry_offset as usize,
+    }
+}
+
+/// Run a low-level FAT operation directly on the SD card.
+fn with_fat<F>(manager: &VM, f: F) -> Result<(), FSError>
+where
+    F: FnOnce(&fat::Volume, &mut SD) -> Result<(), FSError>,
+{
+    let mut res = Ok(());
+    // Accessing the device invalidates the block cache of the volume manager,
+    // so it won't keep using a stale copy of the blocks we modify.
+    // The callback must return the time source, for whatever reason.
+    manager.device(|dev| {
+        res = fat::Volume::open(dev).and_then(|vol| f(&vol, dev));
+        Clock::new()
+    });
+    res
+}
+
+impl fat::BlockDevice for SD {
+    fn read(&mut self, idx: u32, block: &mut fat::Block) -> Result<(), FSError> {
+        let mut blocks = [Block::new()];
+        embedded_sdmmc::BlockDevice::read(self, &mut blocks, BlockIdx(idx))
+            .map_err(|err| FSError::DeviceError(alloc::format!("{err:?}")))?;
+        block.copy_from_slice(&blocks[0].contents);
+        Ok(())
+    }
+
+    fn write(&mut self, idx: u32, block: &fat::Block) -> Result<(), FSError> {
+        let mut blocks = [Block::new()];
+        blocks[0].contents.copy_from_slice(block);
+        embedded_sdmmc::BlockDevice::write(self, &blocks, BlockIdx(idx))
+            .map_err(|err| FSError::DeviceError(alloc::format!("{err:?}")))
+    }
+}
+
 impl Drop for DirImpl {
     fn drop(&mut self) {
         let manager = &self.vm.borrow();
@@ -403,6 +610,23 @@ impl embedded_io::Write for FileW {
     }
 }
 
+impl embedded_io::Read for FileW {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let manager = &self.vm.borrow();
+        match manager.read(self.file, buf) {
+            Ok(size) => Ok(size),
+            Err(_) => Err(embedded_io::ErrorKind::Other),
+        }
+    }
+}
+
+impl embedded_io::Seek for FileW {
+    fn seek(&mut self, pos: embedded_io::SeekFrom) -> Result<u64, Self::Error> {
+        let manager = &self.vm.borrow();
+        seek_file(manager, self.file, pos)
+    }
+}
+
 impl Drop for FileW {
     fn drop(&mut self) {
         let manager = &self.vm.borrow();
@@ -431,17 +655,40 @@ impl embedded_io::Read for FileR {
 impl embedded_io::Seek for FileR {
     fn seek(&mut self, pos: embedded_io::SeekFrom) -> Result<u64, Self::Error> {
         let manager = &self.vm.borrow();
-        use embedded_io::SeekFrom::*;
-        let res = match pos {
-            Start(n) => manager.file_seek_from_start(self.file, n as u32),
-            End(n) => manager.file_seek_from_end(self.file, n as u32),
-            Current(n) => manager.file_seek_from_current(self.file, n as i32),
-        };
-        let res = res.and_then(|_| manager.file_offset(self.file));
-        match res {
-            Ok(size) => Ok(size as u64),
-            Err(_) => Err(embedded_io::ErrorKind::Other),
+        seek_file(manager, self.file, pos)
+    }
+}
+
+/// Move the file cursor and return the new offset from the file start.
+fn seek_file(
+    manager: &VM,
+    file: RawFile,
+    pos: embedded_io::SeekFrom,
+) -> Result<u64, embedded_io::ErrorKind> {
+    use embedded_io::ErrorKind::InvalidInput;
+    use embedded_io::SeekFrom::*;
+    // FAT files are limited to 4 GB, so bigger offsets are always invalid.
+    let res = match pos {
+        Start(n) => {
+            let n = u32::try_from(n).map_err(|_| InvalidInput)?;
+            manager.file_seek_from_start(file, n)
+        }
+        // embedded-sdmmc doesn't support seeking past the end.
+        End(n) if n > 0 => return Err(InvalidInput),
+        // embedded-sdmmc expects the offset backwards from the end.
+        End(n) => {
+            let n = u32::try_from(n.unsigned_abs()).map_err(|_| InvalidInput)?;
+            manager.file_seek_from_end(file, n)
         }
+        Current(n) => {
+            let n = i32::try_from(n).map_err(|_| InvalidInput)?;
+            manager.file_seek_from_current(file, n)
+        }
+    };
+    let res = res.and_then(|_| manager.file_offset(file));
+    match res {
+        Ok(size) => Ok(size as u64),
+        Err(_) => Err(embedded_io::ErrorKind::Other),
     }
 }
 
@@ -541,6 +788,24 @@ impl Network for DeviceImpl<'_> {
         Ok(())
     }
 
+    fn net_set_advertisement(&mut self, _: Advertisement) -> NetworkResult<()> {
+        // TODO: the firefly-io protocol doesn't support advertisement payload yet.
+        Err(NetworkError::Error(
+            "advertisement payload is not supported",
+        ))
+    }
+
+    fn net_peers(&mut self) -> NetworkResult<Vec<Peer<Self::Addr>>> {
+        // TODO: the firefly-io protocol doesn't support advertisement payload yet.
+        Err(NetworkError::Error("listing peers is not supported"))
+    }
+
+    fn net_set_key(&mut self, _: Option<SessionKey>) -> NetworkResult<()> {
+        Err(NetworkError::Error(
+            "message authentication is not supported",
+        ))
+    }
+
     fn net_recv(&mut self) -> NetworkResult<Option<(Self::Addr, Box<[u8]>)>> {
         let req = firefly_types::spi::Request::NetRecv;
         let raw = self.io_transfer(req)?;
@@ -557,11 +822,22 @@ impl Network for DeviceImpl<'_> {
     }
 
     fn net_send(&mut self, addr: Self::Addr, data: &[u8]) -> NetworkResult<()> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(NetworkError::OutMessageTooBig);
+        }
         let req = firefly_types::spi::Request::NetSend(addr, data);
         self.io_send(req)?;
         Ok(())
     }
 
+    fn net_send_reliable(&mut self, _: Self::Addr, _: &[u8]) -> NetworkResult<()> {
+        Err(NetworkError::Error("reliable delivery is not supported"))
+    }
+
+    fn net_max_message_size(&self) -> usize {
+        MAX_MESSAGE_SIZE
+    }
+
     fn net_send_status(
         &mut self,
         addr: Self::Addr,
@@ -583,40 +859,45 @@ impl Serial for DeviceImpl<'_> {
     }
 
     fn serial_stop(&mut self) -> NetworkResult<()> {
+        self.serial_frames = framing::Deframer::new(framing::SERIAL_MAX_SIZE);
         Ok(())
     }
 
-    fn serial_recv(&mut self) -> NetworkResult<Option<Box<[u8]>>> {
-        let mut buf = Vec::new();
+    fn serial_recv_event(&mut self) -> NetworkResult<Option<SerialEvent>> {
+        // The runtime polls for messages on every update,
+        // so it's a good place to continue writing the queued messages.
+        self.flush_serial();
+        // Read only up to the end of the first complete frame.
+        // The rest of the bytes stay in the USB buffer until the next call.
         while let Ok(byte) = self.usb_serial.read_byte() {
-            buf.push(byte);
-        }
-        if buf.is_empty() {
-            return Ok(None);
+            if let Some(res) = self.serial_frames.push(byte) {
+                let data = res?;
+                return Ok(Some(SerialEvent::Message(0, data)));
+            }
         }
-        Ok(Some(buf.into_boxed_slice()))
+        Ok(None)
     }
 
-    fn serial_send(&mut self, data: &[u8]) -> NetworkResult<()> {
-        send_to_serial(&mut self.usb_serial, data);
+    fn serial_send_to(&mut self, client: Option<ClientId>, data: &[u8]) -> NetworkResult<()> {
+        // The USB host is the only client.
+        if client.is_some_and(|client| client != 0) {
+            return Ok(());
+        }
+        if data.len() > framing::SERIAL_MAX_SIZE {
+            return Err(NetworkError::OutMessageTooBig);
+        }
+        self.serial_out.push(&framing::encode(data));
+        self.flush_serial();
         Ok(())
     }
-}
 
-fn send_to_serial(usb: &mut UsbSerialJtag<'static, Blocking>, data: &[u8]) {
-    let n = cobs::max_encoding_length(data.len());
-    let mut buf = alloc::vec![0; n];
-    cobs::encode(data, &mut buf);
-    // Non-blocking writes ensure that we won't block forever
-    // if there is no client connected listening for messages.
-    // However, that also means we might lose some messages
-    // even if there is a client connected
-    // (if the runtime writes faster than the client reads).
-    for byte in buf {
-        _ = usb.write_byte_nb(byte);
+    fn serial_queued(&self) -> usize {
+        self.serial_out.len()
+    }
+
+    fn serial_dropped(&self) -> usize {
+        self.serial_out.dropped()
     }
-    _ = usb.write_byte_nb(0x00);
-    _ = usb.flush_tx_nb();
 }
 
 impl Wifi for DeviceImpl<'_> {
@@ -666,9 +947,12 @@ impl Wifi for DeviceImpl<'_> {
         Ok(())
     }
 
-    fn tcp_connect(&mut self, ip: u32, port: u16) -> NetworkResult<()> {
+    fn tcp_connect(&mut self, ip: IpAddr, port: u16) -> NetworkResult<()> {
         use firefly_types::spi::{Request, Response};
-        let req = Request::TcpConnect(ip, port);
+        let IpAddr::V4(ip) = ip else {
+            return Err(NetworkError::Error("IPv6 is not supported"));
+        };
+        let req = Request::TcpConnect(ip.to_bits(), port);
         let raw = self.io_transfer(req)?;
         let resp = self.io_decode(&raw)?;
         if resp != Response::TcpConnected {
@@ -723,17 +1007,77 @@ impl Wifi for DeviceImpl<'_> {
     }
 }
 
-struct FakeTimesource {}
+/// Wall clock derived from the monotonic timer and the last synced UTC time.
+///
+/// The clock is shared with the [VolumeManager] which uses it
+/// to set creation and modification timestamps of files on the SD card.
+#[derive(Clone)]
+struct Clock {
+    /// Seconds since the Unix epoch at the moment of the device boot.
+    ///
+    /// None if the time was never synced.
+    base: Rc<Cell<Option<u64>>>,
+}
 
-impl embedded_sdmmc::TimeSource for FakeTimesource {
+impl Clock {
+    fn new() -> Self {
+        Self {
+            base: Rc::new(Cell::new(None)),
+        }
+    }
+
+    fn uptime() -> u64 {
+        let now = esp_hal::time::Instant::now();
+        now.duration_since_epoch().as_secs()
+    }
+
+    fn now_utc(&self) -> Option<DateTime> {
+        let base = self.base.get()?;
+        Some(DateTime::from_unix(base + Self::uptime()))
+    }
+
+    fn set_utc(&self, now: DateTime) {
+        let Some(now) = now.to_unix() else {
+            return;
+        };
+        let base = now.saturating_sub(Self::uptime());
+        self.base.set(Some(base));
+    }
+}
+
+impl embedded_sdmmc::TimeSource for Clock {
     fn get_timestamp(&self) -> embedded_sdmmc::Timestamp {
+        // If the time was never synced, the files are dated with the Unix epoch.
+        // FAT can't store it and so it becomes 1980-01-01 which is read back as unknown.
+        let mut now = self.now_utc().unwrap_or(DateTime::from_unix(0));
+        // FAT dates can only hold the years 1980-2107 with 2 seconds precision.
+        // Dates out of the range are clamped to its first or last moment.
+        if now.year < 1980 {
+            now = DateTime {
+                year: 1980,
+                month: 1,
+                day: 1,
+                hour: 0,
+                minute: 0,
+                second: 0,
+            };
+        } else if now.year > 2107 {
+            now = DateTime {
+                year: 2107,
+                month: 12,
+                day: 31,
+                hour: 23,
+                minute: 59,
+                second: 58,
+            };
+        }
         embedded_sdmmc::Timestamp {
-            year_since_1970: 0,
-            zero_indexed_month: 0,
-            zero_indexed_day: 0,
-            hours: 0,
-            minutes: 0,
-            seconds: 0,
+            year_since_1970: (now.year - 1970) as u8,
+            zero_indexed_month: now.month - 1,
+            zero_indexed_day: now.day - 1,
+            hours: now.hour,
+            minutes: now.minute,
+            seconds: now.second,
         }
     }
 }
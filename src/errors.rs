@@ -264,6 +264,7 @@ impl fmt::Display for FSError {
     }
 }
 
+#[derive(Debug)]
 pub enum NetworkError {
     NotInitialized,
     AlreadyInitialized,
@@ -273,7 +274,10 @@ pub enum NetworkError {
     RecvError,
     SendError,
     NetThreadDeallocated,
+    NetThreadPanicked,
     OutMessageTooBig,
+    InMessageTooBig,
+    BadFrame,
     UnexpectedResp,
     Decode(postcard::Error),
     Uart(&'static str),
@@ -356,7 +360,10 @@ impl fmt::Display for NetworkError {
             RecvError => write!(f, "cannot fetch network message"),
             SendError => write!(f, "cannot send network message"),
             NetThreadDeallocated => write!(f, "thread handling networking is already deallocated"),
+            NetThreadPanicked => write!(f, "thread handling networking has crashed"),
             OutMessageTooBig => write!(f, "outgoing message is too big"),
+            InMessageTooBig => write!(f, "incoming message is too big and was dropped"),
+            BadFrame => write!(f, "incoming message is corrupted and was dropped"),
             UnexpectedResp => write!(f, "unexpected response"),
             Decode(err) => write!(f, "decode message: {err}"),
             Uart(err) => write!(f, "SPI error: {err}"),
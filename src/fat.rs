@@ -0,0 +1,773 @@
+//! Direct editing of FAT16/FAT32 directory entries and allocation tables.
+//!
+//! embedded-sdmmc can neither rename a file nor delete a directory.
+//! This module fills the gap by working with the raw blocks of the SD card.
+//! It is used only for these two operations, everything else
+//! goes through embedded-sdmmc.
+//!
+//! The code is generic over [BlockDevice] so that it can be tested
+//! on the host against an in-memory disk image.
+use crate::errors::FSError;
+use alloc::vec::Vec;
+
+/// The size of a block (sector) in bytes. Other sizes are not supported.
+pub const BLOCK_SIZE: usize = 512;
+
+pub type Block = [u8; BLOCK_SIZE];
+
+/// The size of a directory entry in bytes.
+const ENTRY_SIZE: usize = 32;
+
+/// The first byte of the name of a deleted directory entry.
+const DELETED: u8 = 0xE5;
+
+/// The attributes of a directory entry holding a part of a long file name.
+const LFN_ATTRS: u8 = 0x0F;
+
+/// The value of an FSInfo field that means "unknown".
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// Raw access to the blocks of the storage.
+pub trait BlockDevice {
+    fn read(&mut self, idx: u32, block: &mut Block) -> Result<(), FSError>;
+    fn write(&mut self, idx: u32, block: &Block) -> Result<(), FSError>;
+}
+
+/// The position of a directory entry on the storage.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EntryPos {
+    /// The absolute index of the block containing the entry.
+    pub block: u32,
+    /// The offset of the entry in bytes from the beginning of the block.
+    pub offset: usize,
+}
+
+/// The layout of a FAT volume.
+pub struct Volume {
+    /// The absolute index of the first block of the first FAT.
+    fat_start: u32,
+    /// The size of a single FAT in blocks.
+    fat_size: u32,
+    num_fats: u32,
+    /// The total number of data clusters.
+    cluster_count: u32,
+    blocks_per_cluster: u32,
+    /// The absolute index of the first block of the cluster 2.
+    data_start: u32,
+    /// The absolute index of the first block of the FAT16 root directory.
+    root_start: u32,
+    /// The size of the FAT16 root directory in blocks.
+    root_blocks: u32,
+    /// The first cluster of the FAT32 root directory.
+    root_cluster: u32,
+    /// The absolute index of the FAT32 FSInfo block, if any.
+    fsinfo: Option<u32>,
+    fat32: bool,
+}
+
+impl Volume {
+    /// Read the layout of the first partition.
+    pub fn open<D: BlockDevice>(dev: &mut D) -> Result<Self, FSError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        dev.read(0, &mut block)?;
+        if read_u16(&block, 510) != 0xAA55 {
+            return Err(FSError::FormatError("Invalid MBR signature"));
+        }
+        let lba_start = read_u32(&block, 446 + 8);
+
+        dev.read(lba_start, &mut block)?;
+        if read_u16(&block, 510) != 0xAA55 {
+            return Err(FSError::FormatError("Bad BPB footer"));
+        }
+        let bytes_per_block = read_u16(&block, 11);
+        if usize::from(bytes_per_block) != BLOCK_SIZE {
+            return Err(FSError::BadBlockSize(bytes_per_block));
+        }
+        let blocks_per_cluster = u32::from(block[13]);
+        let reserved = u32::from(read_u16(&block, 14));
+        let num_fats = u32::from(block[16]);
+        let root_entries = u32::from(read_u16(&block, 17));
+        let fat_size = match read_u16(&block, 22) {
+            0 => read_u32(&block, 36),
+            size => u32::from(size),
+        };
+        let total_blocks = match read_u16(&block, 19) {
+            0 => read_u32(&block, 32),
+            total => u32::from(total),
+        };
+        if blocks_per_cluster == 0 {
+            return Err(FSError::FormatError("Invalid FAT format"));
+        }
+
+        let root_dir_blocks = (root_entries * ENTRY_SIZE as u32).div_ceil(BLOCK_SIZE as u32);
+        let non_data_blocks = reserved + num_fats * fat_size + root_dir_blocks;
+        let Some(data_blocks) = total_blocks.checked_sub(non_data_blocks) else {
+            return Err(FSError::FormatError("Invalid FAT format"));
+        };
+        let cluster_count = data_blocks / blocks_per_cluster;
+        // The FAT type is determined by the number of clusters,
+        // the same way as embedded-sdmmc does it.
+        if cluster_count < 4085 {
+            return Err(FSError::FormatError("FAT12 is unsupported"));
+        }
+        let fat32 = cluster_count >= 65525;
+        let fsinfo = match read_u16(&block, 48) {
+            _ if !fat32 => None,
+            0 | 0xFFFF => None,
+            idx => Some(lba_start + u32::from(idx)),
+        };
+        let fat_start = lba_start + reserved;
+        let root_start = fat_start + num_fats * fat_size;
+        Ok(Self {
+            fat_start,
+            fat_size,
+            num_fats,
+            cluster_count,
+            blocks_per_cluster,
+            data_start: root_start + root_dir_blocks,
+            root_start,
+            root_blocks: root_dir_blocks,
+            root_cluster: read_u32(&block, 44),
+            fsinfo,
+            fat32,
+        })
+    }
+
+    /// Replace the file at `to` with the file at `from`.
+    ///
+    /// Both entries must be in the directory `dir`, which is the position
+    /// of an entry pointing to the directory: either its entry in the parent
+    /// directory or its own "." entry. None for the root directory.
+    ///
+    /// The entry of `to` keeps its name and attributes but gets the content
+    /// (the first cluster, the size, and the modification time) of `from`.
+    /// The entry of `from` (including its long name) is marked as deleted
+    /// and the old content of `to` is freed.
+    ///
+    /// If both entries are in the same block (which is almost always the case
+    /// for small directories), both are updated with a single block write.
+    /// Otherwise, `from` is deleted first, so an interruption may lose `from`
+    /// and leak its clusters but `to` is always either the old or the new file
+    /// and no cluster is ever shared by two files.
+    pub fn replace<D: BlockDevice>(
+        &self,
+        dev: &mut D,
+        dir: Option<EntryPos>,
+        from: EntryPos,
+        to: EntryPos,
+    ) -> Result<(), FSError> {
+        if from == to {
+            return Ok(());
+        }
+        let long_name = self.find_long_name(dev, dir, from)?;
+        self.mark_deleted(dev, &long_name)?;
+        let mut block = [0u8; BLOCK_SIZE];
+        dev.read(from.block, &mut block)?;
+        let mut src = [0u8; ENTRY_SIZE];
+        src.copy_from_slice(&block[from.offset..from.offset + ENTRY_SIZE]);
+        block[from.offset] = DELETED;
+        if from.block != to.block {
+            dev.write(from.block, &block)?;
+            dev.read(to.block, &mut block)?;
+        }
+        let dst = &mut block[to.offset..to.offset + ENTRY_SIZE];
+        let old_cluster = self.entry_cluster(dst);
+        // cluster (high), modification time and date, cluster (low), size
+        dst[20..22].copy_from_slice(&src[20..22]);
+        dst[22..32].copy_from_slice(&src[22..32]);
+        dev.write(to.block, &block)?;
+        self.free_chain(dev, old_cluster)
+    }
+
+    /// Mark the directory entry as deleted and free all clusters it occupies.
+    ///
+    /// The entry must be in the directory `dir` (see [Volume::replace]).
+    /// The entries holding the long name of the entry are deleted as well.
+    pub fn remove<D: BlockDevice>(
+        &self,
+        dev: &mut D,
+        dir: Option<EntryPos>,
+        pos: EntryPos,
+    ) -> Result<(), FSError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        dev.read(pos.block, &mut block)?;
+        let cluster = self.entry_cluster(&block[pos.offset..pos.offset + ENTRY_SIZE]);
+        let mut entries = self.find_long_name(dev, dir, pos)?;
+        entries.push(pos);
+        self.mark_deleted(dev, &entries)?;
+        self.free_chain(dev, cluster)
+    }
+
+    /// Find the entries holding the long name of the entry at the given position.
+    ///
+    /// These are the entries right before it with the checksum of its short name.
+    fn find_long_name<D: BlockDevice>(
+        &self,
+        dev: &mut D,
+        dir: Option<EntryPos>,
+        pos: EntryPos,
+    ) -> Result<Vec<EntryPos>, FSError> {
+        let cluster = match dir {
+            Some(dir) => {
+                let mut block = [0u8; BLOCK_SIZE];
+                dev.read(dir.block, &mut block)?;
+                self.entry_cluster(&block[dir.offset..dir.offset + ENTRY_SIZE])
+            }
+            None => 0,
+        };
+        // The positions and checksums of the long name entries
+        // going right before the current entry.
+        let mut run: Vec<(EntryPos, u8)> = Vec::new();
+        let mut checksum = None;
+        self.walk_dir(dev, cluster, |idx, block| {
+            for offset in (0..BLOCK_SIZE).step_by(ENTRY_SIZE) {
+                let entry = &block[offset..offset + ENTRY_SIZE];
+                let here = EntryPos { block: idx, offset };
+                if here == pos {
+                    checksum = Some(lfn_checksum(&entry[..11]));
+                    return false;
+                }
+                // The end of the directory.
+                if entry[0] == 0 {
+                    return false;
+                }
+                if entry[0] != DELETED && entry[11] == LFN_ATTRS {
+                    run.push((here, entry[13]));
+                } else {
+                    run.clear();
+                }
+            }
+            true
+        })?;
+        let Some(checksum) = checksum else {
+            return Ok(Vec::new());
+        };
+        // Long name entries with another checksum belong to a deleted
+        // or corrupted entry, leave them alone.
+        let matching = run.iter().rev().take_while(|(_, sum)| *sum == checksum);
+        let start = run.len() - matching.count();
+        Ok(run[start..].iter().map(|(pos, _)| *pos).collect())
+    }
+
+    /// Call the callback for each block of the directory, in order,
+    /// until it returns false.
+    ///
+    /// The cluster 0 means the root directory.
+    fn walk_dir<D, F>(&self, dev: &mut D, cluster: u32, mut f: F) -> Result<(), FSError>
+    where
+        D: BlockDevice,
+        F: FnMut(u32, &Block) -> bool,
+    {
+        let mut block = [0u8; BLOCK_SIZE];
+        if cluster == 0 && !self.fat32 {
+            for idx in self.root_start..self.root_start + self.root_blocks {
+                dev.read(idx, &mut block)?;
+                if !f(idx, &block) {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        let mut cluster = if cluster == 0 {
+            self.root_cluster
+        } else {
+            cluster
+        };
+        // The limit protects from looping forever on a corrupted FAT.
+        for _ in 0..self.cluster_count {
+            if !self.is_data_cluster(cluster) {
+                break;
+            }
+            let first = self.data_start + (cluster - 2) * self.blocks_per_cluster;
+            for idx in first..first + self.blocks_per_cluster {
+                dev.read(idx, &mut block)?;
+                if !f(idx, &block) {
+                    return Ok(());
+                }
+            }
+            cluster = self.next_cluster(dev, cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Mark the directory entries as deleted.
+    ///
+    /// Entries in the same block are updated with a single write
+    /// if they go one after another.
+    fn mark_deleted<D: BlockDevice>(
+        &self,
+        dev: &mut D,
+        entries: &[EntryPos],
+    ) -> Result<(), FSError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut loaded: Option<u32> = None;
+        for pos in entries {
+            if loaded != Some(pos.block) {
+                if let Some(loaded) = loaded {
+                    dev.write(loaded, &block)?;
+                }
+                dev.read(pos.block, &mut block)?;
+                loaded = Some(pos.block);
+            }
+            block[pos.offset] = DELETED;
+        }
+        if let Some(loaded) = loaded {
+            dev.write(loaded, &block)?;
+        }
+        Ok(())
+    }
+
+    /// The first cluster of the file or directory described by the entry.
+    fn entry_cluster(&self, entry: &[u8]) -> u32 {
+        let low = u32::from(read_u16(entry, 26));
+        if self.fat32 {
+            let high = u32::from(read_u16(entry, 20));
+            (high << 16) | low
+        } else {
+            low
+        }
+    }
+
+    /// Read the FAT to get the cluster that follows the given one.
+    fn next_cluster<D: BlockDevice>(&self, dev: &mut D, cluster: u32) -> Result<u32, FSError> {
+        let entry_size: u32 = if self.fat32 { 4 } else { 2 };
+        let offset = cluster * entry_size;
+        let mut block = [0u8; BLOCK_SIZE];
+        dev.read(self.fat_start + offset / BLOCK_SIZE as u32, &mut block)?;
+        let offset = (offset % BLOCK_SIZE as u32) as usize;
+        if self.fat32 {
+            Ok(read_u32(&block, offset) & 0x0FFF_FFFF)
+        } else {
+            Ok(u32::from(read_u16(&block, offset)))
+        }
+    }
+
+    /// Mark as free all clusters in the chain starting at the given cluster.
+    ///
+    /// All copies of the FAT are updated. On FAT32, the free cluster count
+    /// and the next free cluster hint in FSInfo are reset to unknown,
+    /// so that they don't point at wrong clusters.
+    fn free_chain<D: BlockDevice>(&self, dev: &mut D, first: u32) -> Result<(), FSError> {
+        let entry_size: u32 = if self.fat32 { 4 } else { 2 };
+        let mut block = [0u8; BLOCK_SIZE];
+        // The FAT block (relative to the FAT start) currently in the buffer.
+        let mut loaded: Option<u32> = None;
+        let mut cluster = first;
+        // The chain can't be longer than the number of clusters.
+        // The limit protects from looping forever on a corrupted FAT.
+        for _ in 0..self.cluster_count {
+            if !self.is_data_cluster(cluster) {
+                break;
+            }
+            let offset = cluster * entry_size;
+            let fat_block = offset / BLOCK_SIZE as u32;
+            let offset = (offset % BLOCK_SIZE as u32) as usize;
+            if loaded != Some(fat_block) {
+                if let Some(loaded) = loaded {
+                    self.write_fat_block(dev, loaded, &block)?;
+                }
+                dev.read(self.fat_start + fat_block, &mut block)?;
+                loaded = Some(fat_block);
+            }
+            if self.fat32 {
+                let raw = read_u32(&block, offset);
+                // The highest 4 bits are reserved and must be preserved.
+                block[offset..offset + 4].copy_from_slice(&(raw & 0xF000_0000).to_le_bytes());
+                cluster = raw & 0x0FFF_FFFF;
+            } else {
+                cluster = u32::from(read_u16(&block, offset));
+                block[offset..offset + 2].copy_from_slice(&[0, 0]);
+            }
+        }
+        if let Some(loaded) = loaded {
+            self.write_fat_block(dev, loaded, &block)?;
+            self.invalidate_fsinfo(dev)?;
+        }
+        Ok(())
+    }
+
+    /// Mark the free cluster count and the next free cluster in FSInfo as unknown.
+    fn invalidate_fsinfo<D: BlockDevice>(&self, dev: &mut D) -> Result<(), FSError> {
+        let Some(idx) = self.fsinfo else {
+            return Ok(());
+        };
+        let mut block = [0u8; BLOCK_SIZE];
+        dev.read(idx, &mut block)?;
+        if read_u32(&block, 0) != 0x4161_5252 || read_u32(&block, 484) != 0x6141_7272 {
+            return Ok(());
+        }
+        if read_u32(&block, 488) == FSINFO_UNKNOWN && read_u32(&block, 492) == FSINFO_UNKNOWN {
+            return Ok(());
+        }
+        block[488..492].copy_from_slice(&FSINFO_UNKNOWN.to_le_bytes());
+        block[492..496].copy_from_slice(&FSINFO_UNKNOWN.to_le_bytes());
+        dev.write(idx, &block)
+    }
+
+    /// Check if the FAT value points to a cluster rather than being a special marker
+    /// (free, bad, or end of chain).
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    /// Write the block of the FAT into all copies of the FAT.
+    fn write_fat_block<D: BlockDevice>(
+        &self,
+        dev: &mut D,
+        idx: u32,
+        block: &Block,
+    ) -> Result<(), FSError> {
+        for fat in 0..self.num_fats {
+            dev.write(self.fat_start + fat * self.fat_size + idx, block)?;
+        }
+        Ok(())
+    }
+}
+
+/// The checksum of the short name stored in each of its long name entries.
+fn lfn_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b))
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let bytes = [
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ];
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// A sparse in-memory disk. Blocks that were never written are zeroed.
+    #[derive(Default)]
+    struct Disk {
+        blocks: HashMap<u32, Block>,
+    }
+
+    impl BlockDevice for Disk {
+        fn read(&mut self, idx: u32, block: &mut Block) -> Result<(), FSError> {
+            *block = self.blocks.get(&idx).copied().unwrap_or([0; BLOCK_SIZE]);
+            Ok(())
+        }
+
+        fn write(&mut self, idx: u32, block: &Block) -> Result<(), FSError> {
+            self.blocks.insert(idx, *block);
+            Ok(())
+        }
+    }
+
+    impl Disk {
+        fn block(&mut self, idx: u32) -> &mut Block {
+            self.blocks.entry(idx).or_insert([0; BLOCK_SIZE])
+        }
+    }
+
+    const LBA_START: u32 = 8;
+    const RESERVED: u32 = 4;
+    const FAT_SIZE: u32 = 600;
+
+    /// Make a disk with a single partition with two FATs and a cluster per block.
+    fn make_disk(fat32: bool) -> Disk {
+        let mut disk = Disk::default();
+        let mbr = disk.block(0);
+        mbr[446 + 8..446 + 12].copy_from_slice(&LBA_START.to_le_bytes());
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+        let bpb = disk.block(LBA_START);
+        bpb[11..13].copy_from_slice(&512u16.to_le_bytes());
+        bpb[13] = 1;
+        bpb[14..16].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+        bpb[16] = 2;
+        let total: u32 = if fat32 { 70_000 } else { 10_000 };
+        bpb[32..36].copy_from_slice(&total.to_le_bytes());
+        if fat32 {
+            bpb[36..40].copy_from_slice(&FAT_SIZE.to_le_bytes());
+            // The root directory cluster and the FSInfo block.
+            bpb[44..48].copy_from_slice(&2u32.to_le_bytes());
+            bpb[48..50].copy_from_slice(&1u16.to_le_bytes());
+        } else {
+            bpb[17..19].copy_from_slice(&512u16.to_le_bytes());
+            bpb[22..24].copy_from_slice(&(FAT_SIZE as u16).to_le_bytes());
+        }
+        bpb[510..].copy_from_slice(&[0x55, 0xAA]);
+        disk
+    }
+
+    fn write_entry(disk: &mut Disk, pos: EntryPos, name: &[u8; 11], cluster: u32, size: u32) {
+        let block = disk.block(pos.block);
+        let entry = &mut block[pos.offset..pos.offset + ENTRY_SIZE];
+        entry[..11].copy_from_slice(name);
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[22..26].copy_from_slice(&size.to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+    }
+
+    /// Write an entry holding a part of the long name of the given short name.
+    fn write_lfn_entry(disk: &mut Disk, pos: EntryPos, seq: u8, short_name: &[u8; 11]) {
+        let block = disk.block(pos.block);
+        let entry = &mut block[pos.offset..pos.offset + ENTRY_SIZE];
+        entry.fill(b'a');
+        entry[0] = seq;
+        entry[11] = LFN_ATTRS;
+        entry[13] = lfn_checksum(short_name);
+    }
+
+    /// Fill the block with deleted entries, so that it's not the end of the directory.
+    fn fill_deleted(disk: &mut Disk, idx: u32) {
+        let block = disk.block(idx);
+        for offset in (0..BLOCK_SIZE).step_by(ENTRY_SIZE) {
+            block[offset] = DELETED;
+        }
+    }
+
+    fn read_entry(disk: &mut Disk, pos: EntryPos) -> [u8; ENTRY_SIZE] {
+        let block = disk.block(pos.block);
+        block[pos.offset..pos.offset + ENTRY_SIZE]
+            .try_into()
+            .unwrap()
+    }
+
+    /// Write the chain of clusters into both FATs.
+    fn write_chain(disk: &mut Disk, fat32: bool, chain: &[u32]) {
+        for (i, cluster) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).copied().unwrap_or(0x0FFF_FFFF);
+            for fat in 0..2 {
+                write_fat(disk, fat32, fat, *cluster, next);
+            }
+        }
+    }
+
+    fn write_fat(disk: &mut Disk, fat32: bool, fat: u32, cluster: u32, value: u32) {
+        let size = if fat32 { 4 } else { 2 };
+        let offset = (cluster * size) as usize;
+        let block_idx = LBA_START + RESERVED + fat * FAT_SIZE + (offset / BLOCK_SIZE) as u32;
+        let block = disk.block(block_idx);
+        let offset = offset % BLOCK_SIZE;
+        let value = value.to_le_bytes();
+        block[offset..offset + size as usize].copy_from_slice(&value[..size as usize]);
+    }
+
+    fn read_fat(disk: &mut Disk, fat32: bool, fat: u32, cluster: u32) -> u32 {
+        let size = if fat32 { 4 } else { 2 };
+        let offset = (cluster * size) as usize;
+        let block_idx = LBA_START + RESERVED + fat * FAT_SIZE + (offset / BLOCK_SIZE) as u32;
+        let block = disk.block(block_idx);
+        let offset = offset % BLOCK_SIZE;
+        if fat32 {
+            read_u32(block, offset)
+        } else {
+            u32::from(read_u16(block, offset))
+        }
+    }
+
+    #[test]
+    fn test_open() {
+        let mut disk = make_disk(false);
+        let vol = Volume::open(&mut disk).unwrap();
+        assert!(!vol.fat32);
+        assert_eq!(vol.fat_start, LBA_START + RESERVED);
+        // The root directory of 512 entries takes 32 blocks.
+        assert_eq!(vol.cluster_count, 10_000 - RESERVED - 2 * FAT_SIZE - 32);
+
+        let mut disk = make_disk(true);
+        let vol = Volume::open(&mut disk).unwrap();
+        assert!(vol.fat32);
+        assert_eq!(vol.cluster_count, 70_000 - RESERVED - 2 * FAT_SIZE);
+
+        let mut disk = Disk::default();
+        assert!(Volume::open(&mut disk).is_err());
+    }
+
+    #[test]
+    fn test_replace_same_block() {
+        let mut disk = make_disk(false);
+        let vol = Volume::open(&mut disk).unwrap();
+        let from = EntryPos {
+            block: 700,
+            offset: 32,
+        };
+        let to = EntryPos {
+            block: 700,
+            offset: 96,
+        };
+        write_entry(&mut disk, from, b"_REPLACETMP", 10, 1300);
+        write_entry(&mut disk, to, b"STATS   BIN", 20, 700);
+        write_chain(&mut disk, false, &[10, 11, 12]);
+        write_chain(&mut disk, false, &[20, 300]);
+
+        vol.replace(&mut disk, None, from, to).unwrap();
+
+        assert_eq!(read_entry(&mut disk, from)[0], DELETED);
+        let entry = read_entry(&mut disk, to);
+        assert_eq!(&entry[..11], b"STATS   BIN");
+        assert_eq!(vol.entry_cluster(&entry), 10);
+        assert_eq!(read_u32(&entry, 28), 1300);
+        for fat in 0..2 {
+            assert_eq!(read_fat(&mut disk, false, fat, 10), 11);
+            assert_eq!(read_fat(&mut disk, false, fat, 12), 0xFFFF);
+            assert_eq!(read_fat(&mut disk, false, fat, 20), 0);
+            assert_eq!(read_fat(&mut disk, false, fat, 300), 0);
+        }
+    }
+
+    #[test]
+    fn test_replace_fat32() {
+        let mut disk = make_disk(true);
+        let vol = Volume::open(&mut disk).unwrap();
+        let from = EntryPos {
+            block: 2000,
+            offset: 0,
+        };
+        let to = EntryPos {
+            block: 2001,
+            offset: 480,
+        };
+        write_entry(&mut disk, from, b"_REPLACETMP", 0x1_0005, 10);
+        // A file created empty has no clusters.
+        write_entry(&mut disk, to, b"STATS   BIN", 0, 0);
+        write_chain(&mut disk, true, &[0x1_0005]);
+
+        vol.replace(&mut disk, None, from, to).unwrap();
+
+        assert_eq!(read_entry(&mut disk, from)[0], DELETED);
+        let entry = read_entry(&mut disk, to);
+        assert_eq!(vol.entry_cluster(&entry), 0x1_0005);
+        assert_eq!(read_fat(&mut disk, true, 1, 0x1_0005), 0x0FFF_FFFF);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut disk = make_disk(true);
+        let vol = Volume::open(&mut disk).unwrap();
+        let pos = EntryPos {
+            block: 2000,
+            offset: 64,
+        };
+        write_entry(&mut disk, pos, b"SUBDIR     ", 7, 0);
+        // The chain crosses FAT blocks and has the reserved bits set.
+        write_chain(&mut disk, true, &[7, 200, 8]);
+        write_fat(&mut disk, true, 0, 200, 0xF000_0008);
+
+        vol.remove(&mut disk, None, pos).unwrap();
+
+        assert_eq!(read_entry(&mut disk, pos)[0], DELETED);
+        for fat in 0..2 {
+            assert_eq!(read_fat(&mut disk, true, fat, 7), 0);
+            assert_eq!(read_fat(&mut disk, true, fat, 8), 0);
+        }
+        assert_eq!(read_fat(&mut disk, true, 0, 200), 0xF000_0000);
+    }
+
+    #[test]
+    fn test_lfn_checksum() {
+        // Calculated with the reference implementation from the FAT specification.
+        assert_eq!(lfn_checksum(b"LONGFI~1TXT"), 0xD4);
+        assert_eq!(lfn_checksum(&[0; 11]), 0);
+    }
+
+    #[test]
+    fn test_replace_long_name() {
+        let mut disk = make_disk(false);
+        let vol = Volume::open(&mut disk).unwrap();
+        // The subdirectory is the first entry of the root directory
+        // and occupies the cluster 40.
+        let root_start = LBA_START + RESERVED + 2 * FAT_SIZE;
+        let data_start = root_start + 32;
+        let dir = EntryPos {
+            block: root_start,
+            offset: 0,
+        };
+        write_entry(&mut disk, dir, b"SAVES      ", 40, 0);
+        write_chain(&mut disk, false, &[40]);
+        let block = data_start + 40 - 2;
+        let lfn = EntryPos { block, offset: 0 };
+        let from = EntryPos { block, offset: 32 };
+        let to = EntryPos { block, offset: 64 };
+        write_lfn_entry(&mut disk, lfn, 0x41, b"SAVEFI~1BIN");
+        write_entry(&mut disk, from, b"SAVEFI~1BIN", 10, 1300);
+        write_entry(&mut disk, to, b"STATS   BIN", 20, 700);
+        write_chain(&mut disk, false, &[10]);
+        write_chain(&mut disk, false, &[20]);
+
+        vol.replace(&mut disk, Some(dir), from, to).unwrap();
+
+        assert_eq!(read_entry(&mut disk, lfn)[0], DELETED);
+        assert_eq!(read_entry(&mut disk, from)[0], DELETED);
+        let entry = read_entry(&mut disk, to);
+        assert_eq!(&entry[..11], b"STATS   BIN");
+        assert_eq!(vol.entry_cluster(&entry), 10);
+        assert_eq!(read_fat(&mut disk, false, 0, 20), 0);
+    }
+
+    #[test]
+    fn test_remove_long_name() {
+        let mut disk = make_disk(true);
+        let fsinfo = disk.block(LBA_START + 1);
+        fsinfo[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        fsinfo[488..492].copy_from_slice(&100u32.to_le_bytes());
+        fsinfo[492..496].copy_from_slice(&50u32.to_le_bytes());
+        let vol = Volume::open(&mut disk).unwrap();
+
+        // The root directory occupies the clusters 2 and 5 and the long name
+        // is at the end of the first one, right before the short entry.
+        let data_start = LBA_START + RESERVED + 2 * FAT_SIZE;
+        let first = data_start;
+        let second = data_start + 5 - 2;
+        write_chain(&mut disk, true, &[2, 5]);
+        fill_deleted(&mut disk, first);
+        let other = EntryPos {
+            block: first,
+            offset: 0,
+        };
+        write_entry(&mut disk, other, b"OTHER   TXT", 3, 10);
+        // A leftover of another long name.
+        let orphan = EntryPos {
+            block: first,
+            offset: 416,
+        };
+        write_lfn_entry(&mut disk, orphan, 0x41, b"ORPHAN  TXT");
+        let lfn = [
+            EntryPos {
+                block: first,
+                offset: 448,
+            },
+            EntryPos {
+                block: first,
+                offset: 480,
+            },
+        ];
+        write_lfn_entry(&mut disk, lfn[0], 0x42, b"LONGNA~1TXT");
+        write_lfn_entry(&mut disk, lfn[1], 0x01, b"LONGNA~1TXT");
+        let pos = EntryPos {
+            block: second,
+            offset: 0,
+        };
+        write_entry(&mut disk, pos, b"LONGNA~1TXT", 9, 10);
+        write_chain(&mut disk, true, &[9]);
+
+        vol.remove(&mut disk, None, pos).unwrap();
+
+        assert_eq!(read_entry(&mut disk, pos)[0], DELETED);
+        assert_eq!(read_entry(&mut disk, lfn[0])[0], DELETED);
+        assert_eq!(read_entry(&mut disk, lfn[1])[0], DELETED);
+        assert_eq!(read_entry(&mut disk, orphan)[0], 0x41);
+        assert_eq!(&read_entry(&mut disk, other)[..11], b"OTHER   TXT");
+        assert_eq!(read_fat(&mut disk, true, 0, 9), 0);
+        assert_eq!(read_fat(&mut disk, true, 0, 5), 0x0FFF_FFFF);
+        let fsinfo = disk.block(LBA_START + 1);
+        assert_eq!(read_u32(fsinfo, 488), FSINFO_UNKNOWN);
+        assert_eq!(read_u32(fsinfo, 492), FSINFO_UNKNOWN);
+    }
+}
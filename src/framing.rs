@@ -0,0 +1,308 @@
+//! COBS framing of messages sent over a byte stream (serial port or TCP).
+//!
+//! Each message is COBS-encoded, so it doesn't contain zero bytes,
+//! and is followed by a single 0x00 delimiter. The receiving side
+//! feeds every byte it gets into [Deframer] which reassembles the frames
+//! no matter how the stream was split into chunks. The sending side
+//! puts frames into [FrameQueue] and writes them as fast as the stream accepts.
+//!
+//! The module doesn't do any IO and so it's shared by all backends.
+use crate::errors::NetworkError;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// The byte that separates frames in the stream.
+const DELIMITER: u8 = 0x00;
+
+/// The maximum size of a serial message (before encoding).
+pub const SERIAL_MAX_SIZE: usize = 200;
+
+/// How many bytes of outgoing serial messages can wait for a client to read them.
+pub const SERIAL_QUEUE_SIZE: usize = 4096;
+
+/// Encode the message into a frame ready to be written into the stream.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut frame = cobs::encode_vec(data);
+    frame.push(DELIMITER);
+    frame
+}
+
+/// Reassembles and decodes frames from the incoming bytes.
+pub struct Deframer {
+    /// The encoded bytes of the current frame received so far.
+    buf: Vec<u8>,
+    /// The maximum size of an encoded frame, without the delimiter.
+    max_size: usize,
+    /// True if the current frame is too big and its bytes are being skipped.
+    overflow: bool,
+}
+
+impl Deframer {
+    /// Create a deframer for messages of at most the given size (before encoding).
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_size: cobs::max_encoding_length(max_size),
+            overflow: false,
+        }
+    }
+
+    /// Process the next byte from the stream.
+    ///
+    /// Returns the decoded message when the byte is the delimiter closing a frame.
+    /// If the frame is too big or cannot be decoded, the error is returned instead
+    /// and the deframer continues with the next frame. Empty frames are skipped.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Box<[u8]>, NetworkError>> {
+        if byte != DELIMITER {
+            if self.overflow {
+                return None;
+            }
+            if self.buf.len() >= self.max_size {
+                self.overflow = true;
+                self.buf.clear();
+                return None;
+            }
+            self.buf.push(byte);
+            return None;
+        }
+        if self.overflow {
+            self.overflow = false;
+            return Some(Err(NetworkError::InMessageTooBig));
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+        let res = cobs::decode_vec(&self.buf);
+        self.buf.clear();
+        match res {
+            Ok(data) => Some(Ok(data.into_boxed_slice())),
+            Err(_) => Some(Err(NetworkError::BadFrame)),
+        }
+    }
+
+    /// Process a chunk of bytes from the stream, calling the callback for every frame.
+    pub fn extend<F>(&mut self, bytes: &[u8], mut f: F)
+    where
+        F: FnMut(Result<Box<[u8]>, NetworkError>),
+    {
+        for byte in bytes {
+            if let Some(res) = self.push(*byte) {
+                f(res);
+            }
+        }
+    }
+}
+
+/// A bounded queue of encoded frames waiting to be written into the stream.
+///
+/// A frame is either queued as a whole or dropped as a whole,
+/// so a slow reader never receives a truncated frame.
+pub struct FrameQueue {
+    buf: VecDeque<u8>,
+    /// The maximum number of queued bytes.
+    capacity: usize,
+    /// How many frames were dropped because the queue was full.
+    dropped: usize,
+}
+
+impl FrameQueue {
+    /// Create a queue that can hold at most the given number of bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: VecDeque::new(),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    /// Queue the encoded frame (see [encode]).
+    ///
+    /// If there is not enough free space for the whole frame, it is dropped
+    /// and false is returned.
+    pub fn push(&mut self, frame: &[u8]) -> bool {
+        if self.buf.len() + frame.len() > self.capacity {
+            self.dropped = self.dropped.saturating_add(1);
+            return false;
+        }
+        self.buf.extend(frame);
+        true
+    }
+
+    /// Write as many queued bytes as the stream accepts right now.
+    ///
+    /// The callback gets the bytes to write and must return
+    /// how many of them it has written. Returning less than given
+    /// means that the stream is full and the flushing stops.
+    pub fn flush<F>(&mut self, mut write: F)
+    where
+        F: FnMut(&[u8]) -> usize,
+    {
+        while !self.is_empty() {
+            let (head, _) = self.buf.as_slices();
+            let size = head.len();
+            let written = write(head).min(size);
+            self.buf.drain(..written);
+            if written < size {
+                break;
+            }
+        }
+    }
+
+    /// The number of bytes waiting to be written.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// True if there is nothing to write.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// How many frames were dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed the bytes into the deframer and collect all results.
+    fn deframe(deframer: &mut Deframer, bytes: &[u8]) -> Vec<Result<Box<[u8]>, NetworkError>> {
+        let mut results = Vec::new();
+        deframer.extend(bytes, |res| results.push(res));
+        results
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(b"hi"), [3, b'h', b'i', 0]);
+        assert_eq!(encode(&[1, 0, 2]), [2, 1, 2, 2, 0]);
+        assert_eq!(encode(b""), [1, 0]);
+    }
+
+    #[test]
+    fn test_split_frame() {
+        let mut deframer = Deframer::new(SERIAL_MAX_SIZE);
+        let frame = encode(&[1, 0, 2, 3]);
+        for byte in &frame[..frame.len() - 1] {
+            assert!(deframe(&mut deframer, &[*byte]).is_empty());
+        }
+        let results = deframe(&mut deframer, &frame[frame.len() - 1..]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(&results[0].as_ref().unwrap()[..], [1, 0, 2, 3]);
+    }
+
+    #[test]
+    fn test_many_frames_in_chunk() {
+        let mut deframer = Deframer::new(SERIAL_MAX_SIZE);
+        let mut chunk = encode(b"one");
+        chunk.extend(encode(b"two"));
+        chunk.extend(&encode(b"three")[..3]);
+        let results = deframe(&mut deframer, &chunk);
+        assert_eq!(results.len(), 2);
+        assert_eq!(&results[0].as_ref().unwrap()[..], b"one");
+        assert_eq!(&results[1].as_ref().unwrap()[..], b"two");
+
+        // The rest of the third frame comes in the next chunk.
+        let results = deframe(&mut deframer, &encode(b"three")[3..]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(&results[0].as_ref().unwrap()[..], b"three");
+    }
+
+    #[test]
+    fn test_max_size() {
+        let mut deframer = Deframer::new(SERIAL_MAX_SIZE);
+        let data = [7; SERIAL_MAX_SIZE];
+        let results = deframe(&mut deframer, &encode(&data));
+        assert_eq!(results.len(), 1);
+        assert_eq!(&results[0].as_ref().unwrap()[..], data);
+    }
+
+    #[test]
+    fn test_too_big() {
+        let mut deframer = Deframer::new(4);
+        let mut chunk = encode(b"too big");
+        chunk.extend(encode(b"ok"));
+        let results = deframe(&mut deframer, &chunk);
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[0], Err(NetworkError::InMessageTooBig)));
+        assert_eq!(&results[1].as_ref().unwrap()[..], b"ok");
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let mut deframer = Deframer::new(SERIAL_MAX_SIZE);
+        // The first byte claims that the next zero is 5 bytes away,
+        // but the frame ends earlier.
+        let mut chunk = vec![5, 1, 2, 0];
+        chunk.extend(encode(b"ok"));
+        let results = deframe(&mut deframer, &chunk);
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[0], Err(NetworkError::BadFrame)));
+        assert_eq!(&results[1].as_ref().unwrap()[..], b"ok");
+    }
+
+    #[test]
+    fn test_empty_frames() {
+        let mut deframer = Deframer::new(SERIAL_MAX_SIZE);
+        // Consecutive delimiters are skipped.
+        let results = deframe(&mut deframer, &[0, 0, 0]);
+        assert!(results.is_empty());
+        // An encoded empty message decodes into an empty message.
+        let results = deframe(&mut deframer, &encode(b""));
+        assert_eq!(results.len(), 1);
+        assert!(results[0].as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_queue_drops_whole_frames() {
+        let mut queue = FrameQueue::new(10);
+        assert!(queue.is_empty());
+        assert!(queue.push(&encode(b"hello")));
+        assert_eq!(queue.len(), 7);
+        // Doesn't fit, so dropped as a whole.
+        assert!(!queue.push(&encode(b"world")));
+        assert!(!queue.push(&encode(b"!!")));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.len(), 7);
+        assert!(queue.push(&encode(b"x")));
+        assert_eq!(queue.len(), 10);
+    }
+
+    #[test]
+    fn test_queue_partial_flush() {
+        let mut queue = FrameQueue::new(100);
+        queue.push(&encode(b"hello"));
+        queue.push(&encode(b"world"));
+        let mut written = Vec::new();
+
+        // The stream accepts only 3 bytes.
+        let mut calls = 0;
+        queue.flush(|bytes| {
+            calls += 1;
+            let size = bytes.len().min(3);
+            written.extend_from_slice(&bytes[..size]);
+            size
+        });
+        assert_eq!(calls, 1);
+        assert_eq!(queue.len(), 11);
+
+        // The stream is full.
+        queue.flush(|_| 0);
+        assert_eq!(queue.len(), 11);
+
+        queue.flush(|bytes| {
+            written.extend_from_slice(bytes);
+            bytes.len()
+        });
+        assert!(queue.is_empty());
+        let mut deframer = Deframer::new(SERIAL_MAX_SIZE);
+        let results = deframe(&mut deframer, &written);
+        assert_eq!(results.len(), 2);
+        assert_eq!(&results[0].as_ref().unwrap()[..], b"hello");
+        assert_eq!(&results[1].as_ref().unwrap()[..], b"world");
+    }
+}
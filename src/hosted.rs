@@ -1,20 +1,60 @@
 use crate::gamepad::GamepadManager;
+use crate::replay::Session;
 use crate::*;
 use alloc::boxed::Box;
-use core::cell::Cell;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use core::cell::{Cell, RefCell};
 use core::fmt::Display;
 use core::marker::PhantomData;
-use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use firefly_types::spi::SendStatus;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque, hash_map::Entry};
 use std::io::{Read, Write};
-use std::net::{SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
 
 const UDP_PORT_MIN: u16 = 3110;
 const UDP_PORT_MAX: u16 = 3117;
 const TCP_PORT_MIN: u16 = 3210;
 const TCP_PORT_MAX: u16 = 3217;
+/// How many times to try sending a netplay message before giving up.
+const SEND_ATTEMPTS: u8 = 5;
+/// How many unacknowledged netplay messages to keep retrying for each peer.
+const OUTBOX_SIZE: usize = 16;
+/// How long to wait for an acknowledgement before sending the message again.
+const RETRY_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(50);
+/// The maximum size of [Packet] without the payload.
+///
+/// The enum tag, two varints, and the header of the reliable frame,
+/// all wrapped into [Packet::Sealed].
+const PACKET_OVERHEAD: usize = 1 + 5 + 5 + reliable::HEADER_SIZE + SEAL_OVERHEAD;
+/// The maximum payload of a UDP datagram over IPv4.
+const UDP_MAX_PAYLOAD: usize = 65_507;
+/// The upper limit for [DeviceConfig::max_message_size].
+const MAX_MESSAGE_SIZE_LIMIT: usize = UDP_MAX_PAYLOAD - PACKET_OVERHEAD;
+/// The size of [Packet::Sealed] without the sealed packet.
+///
+/// The enum tag, the flag, the nonce, the varint length, and the authentication tag.
+const SEAL_OVERHEAD: usize = 1 + 1 + 12 + 5 + 16;
+/// The extra delay for packets reordered by [NetConditions::reorder].
+const REORDER_DELAY: std::time::Duration = std::time::Duration::from_millis(20);
 const AUDIO_BUF_SIZE: usize = SAMPLE_RATE as usize / 12;
+/// How many captured audio samples to keep if the runtime doesn't read them.
+///
+/// When the buffer is full, the oldest samples are dropped,
+/// so the input never lags behind by more than that.
+const AUDIO_IN_BUF_SIZE: usize = SAMPLE_RATE as usize;
 
 static NAMES: &[&str] = &[
     "j0vial-dharm4",
@@ -37,14 +77,47 @@ pub struct DeviceConfig {
     /// The TCP IP address where to listen for serial events.
     pub tcp_ip: IpAddr,
 
+    /// If provided, the path of a Unix domain socket where to listen for serial events.
+    ///
+    /// Takes precedence over [`DeviceConfig::tcp_ip`]. Use a different path
+    /// for each emulator instance. Supported only on Unix systems.
+    pub serial_socket: Option<PathBuf>,
+
     /// The UDP IP address where to listen for netplay events.
     pub udp_ip: IpAddr,
 
     /// The UDP IP addresses where to send netplay advertisements.
+    ///
+    /// Addresses of a different IP version than [`DeviceConfig::udp_ip`] are skipped.
     pub peers: Vec<IpAddr>,
 
+    /// How to discover other emulators in addition to [`DeviceConfig::peers`].
+    pub discovery: Discovery,
+
+    /// The maximum size in bytes of a netplay message, both outgoing and incoming.
+    ///
+    /// All emulators in the same session must use the same value.
+    /// A message must fit into a single UDP packet, so [Network::net_start]
+    /// fails if the value is bigger than about 64 KB.
+    pub max_message_size: usize,
+
     /// If provided, the path where to save the audio output (as a WAV file).
     pub wav: Option<PathBuf>,
+
+    /// If provided, the path to a WAV file to use as audio input instead of microphone.
+    pub wav_input: Option<PathBuf>,
+
+    /// If provided, the path where to record all input, random numbers,
+    /// timestamps, and audio input.
+    pub record: Option<PathBuf>,
+
+    /// If provided, the path to a recording to replay instead of live input.
+    ///
+    /// Takes precedence over [`DeviceConfig::record`].
+    pub replay: Option<PathBuf>,
+
+    /// Simulated bad network conditions for netplay.
+    pub net_conditions: NetConditions,
 }
 
 impl Default for DeviceConfig {
@@ -53,13 +126,90 @@ impl Default for DeviceConfig {
         Self {
             root: PathBuf::new(),
             tcp_ip: localhost,
+            serial_socket: None,
             udp_ip: localhost,
             peers: vec![localhost],
+            discovery: Discovery::default(),
+            max_message_size: MAX_MESSAGE_SIZE,
             wav: None,
+            wav_input: None,
+            record: None,
+            replay: None,
+            net_conditions: NetConditions::default(),
         }
     }
 }
 
+/// How emulators discover each other for netplay.
+///
+/// For broadcast and multicast, the UDP socket listens on all network interfaces
+/// and [`DeviceConfig::udp_ip`] is used only to pick the interface and the IP version.
+/// If it is a loopback address, the address of the interface
+/// with the default route is used instead.
+///
+/// With IPv6, packets to link-local groups are sent from the link-local address,
+/// so the peers might see the emulator by a different address
+/// than [`Network::net_local_addr`].
+#[derive(Clone, Debug, Default)]
+pub enum Discovery {
+    /// Send advertisements only to [`DeviceConfig::peers`].
+    #[default]
+    Peers,
+
+    /// Also broadcast advertisements to all devices in the local network.
+    ///
+    /// IPv6 has no broadcast, so the all-nodes multicast group (ff02::1) is used instead.
+    Broadcast,
+
+    /// Also send advertisements to the given multicast group.
+    ///
+    /// All emulators must use the same group, for example, 239.255.31.10 or ff02::3110.
+    /// The group must be of the same IP version as [`DeviceConfig::udp_ip`].
+    Multicast(IpAddr),
+}
+
+/// Simulated bad network conditions for testing netplay.
+///
+/// Applied to all outgoing UDP packets, including acknowledgements
+/// and advertisements. The default value doesn't affect the network.
+/// Probabilities out of range are clamped, and [Network::net_start]
+/// fails if any of them is NaN.
+#[derive(Clone, Debug, Default)]
+pub struct NetConditions {
+    /// The probability (from 0.0 to 1.0) that a packet is lost.
+    pub loss: f64,
+
+    /// The delay added to every packet.
+    pub latency: std::time::Duration,
+
+    /// The maximum random delay added to every packet on top of the latency.
+    pub jitter: std::time::Duration,
+
+    /// The probability (from 0.0 to 1.0) that a packet is sent twice.
+    pub duplicate: f64,
+
+    /// The probability (from 0.0 to 1.0) that a packet is held back
+    /// and arrives after the packets sent later.
+    pub reorder: f64,
+
+    /// The seed for the random number generator.
+    ///
+    /// The same seed with the same conditions produces the same
+    /// sequence of losses, delays, and duplicates.
+    pub seed: u64,
+}
+
+impl NetConditions {
+    /// True if packets are sent as is.
+    fn is_ideal(&self) -> bool {
+        self.loss <= 0.
+            && self.latency.is_zero()
+            && self.jitter.is_zero()
+            && self.duplicate <= 0.
+            && self.reorder <= 0.
+    }
+}
+
 pub struct DeviceImpl<'a> {
     config: DeviceConfig,
     /// The time at which the device instance was created.
@@ -68,6 +218,17 @@ pub struct DeviceImpl<'a> {
     gamepad: GamepadManager,
     /// The audio buffer
     audio: Option<AudioWriter>,
+    /// The audio input. Started on the first read.
+    #[cfg(not(target_os = "android"))]
+    audio_in: Option<AudioInput>,
+    /// If true, the audio input failed to start and shouldn't be retried.
+    #[cfg(not(target_os = "android"))]
+    audio_in_failed: bool,
+    /// The audio input samples returned by the last read.
+    #[cfg(not(target_os = "android"))]
+    audio_in_buf: Vec<i16>,
+    /// Recording or replaying of input, random numbers, timestamps, and audio input.
+    session: RefCell<Session>,
     wifi_status: u8,
     network: NetworkImpl<'a>,
     serial: SerialImpl,
@@ -80,10 +241,24 @@ impl<'a> DeviceImpl<'a> {
         let audio = start_audio(&config);
         #[cfg(target_os = "android")]
         let audio = None;
+        let session = if let Some(path) = &config.replay {
+            Session::replay(path)
+        } else if let Some(path) = &config.record {
+            Session::record(path)
+        } else {
+            Session::Live
+        };
         Self {
             start: std::time::Instant::now(),
             gamepad: GamepadManager::new(),
             audio,
+            #[cfg(not(target_os = "android"))]
+            audio_in: None,
+            #[cfg(not(target_os = "android"))]
+            audio_in_failed: false,
+            #[cfg(not(target_os = "android"))]
+            audio_in_buf: Vec::new(),
+            session: RefCell::new(session),
             config,
             wifi_status: 2,
             network: NetworkImpl::new(),
@@ -106,32 +281,47 @@ impl<'a> Device for DeviceImpl<'a> {
     type Dir = DirImpl;
 
     fn now(&self) -> Instant {
-        let now = std::time::Instant::now();
-        let dur = now.duration_since(self.start);
-        Instant {
-            us: dur.as_micros() as u32,
-        }
+        let us = self.session.borrow_mut().now(|| {
+            let now = std::time::Instant::now();
+            let dur = now.duration_since(self.start);
+            dur.as_micros() as u64
+        });
+        Instant { us }
     }
 
     fn delay(&self, d: Duration) {
-        let dur = core::time::Duration::from_micros(d.us as u64);
-        std::thread::sleep(dur);
+        std::thread::sleep(d.into());
+    }
+
+    fn now_utc(&self) -> Option<DateTime> {
+        let secs = self.session.borrow_mut().now_utc(|| {
+            let now = std::time::SystemTime::now();
+            let since_epoch = now.duration_since(std::time::UNIX_EPOCH).ok()?;
+            Some(since_epoch.as_secs())
+        });
+        secs.map(DateTime::from_unix)
+    }
+
+    fn set_utc(&mut self, _now: DateTime) {
+        // The system clock is managed by the OS.
     }
 
     fn read_input(&mut self) -> Option<InputState> {
-        self.gamepad.read_input()
+        let gamepad = &mut self.gamepad;
+        self.session.get_mut().read_input(|| gamepad.read_input())
     }
 
     fn get_name(&mut self) -> Option<&'static str> {
         let addr = self.network.local_addr?;
-        let IpAddr::V4(ip) = addr.ip() else {
-            return None;
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => u128::from(ip.to_bits()),
+            IpAddr::V6(ip) => ip.to_bits(),
         };
         let port = addr.port();
         if port == UDP_PORT_MIN {
             return None;
         }
-        let hash = (ip.to_bits() + u32::from(port)) % 10;
+        let hash = ip.wrapping_add(u128::from(port)) % 10;
         let name = NAMES[hash as usize];
         Some(name)
     }
@@ -145,7 +335,7 @@ impl<'a> Device for DeviceImpl<'a> {
     }
 
     fn random(&mut self) -> u32 {
-        rand::random()
+        self.session.get_mut().random(rand::random)
     }
 
     fn open_dir(&mut self, path: &[&str]) -> Result<Self::Dir, FSError> {
@@ -171,6 +361,27 @@ impl<'a> Device for DeviceImpl<'a> {
         }
     }
 
+    #[cfg(target_os = "android")]
+    fn read_audio(&mut self) -> &[i16] {
+        &[]
+    }
+
+    #[cfg(not(target_os = "android"))]
+    fn read_audio(&mut self) -> &[i16] {
+        // When replaying, the microphone is never started.
+        let session = self.session.get_mut();
+        session.read_audio(&mut self.audio_in_buf, |buf| {
+            if self.audio_in.is_none() && !self.audio_in_failed {
+                self.audio_in = start_audio_input(&self.config);
+                self.audio_in_failed = self.audio_in.is_none();
+            }
+            if let Some(audio_in) = &mut self.audio_in {
+                audio_in.read(buf);
+            }
+        });
+        &self.audio_in_buf
+    }
+
     #[cfg(target_os = "android")]
     fn get_battery_status(&mut self) -> Option<BatteryStatus> {
         None
@@ -193,6 +404,7 @@ pub struct DirImpl {
 impl Dir for DirImpl {
     type Read = File;
     type Write = File;
+    type ReadWrite = File;
 
     fn open_file(&mut self, name: &str) -> Result<Self::Read, FSError> {
         let path = self.path.join(name);
@@ -216,12 +428,25 @@ impl Dir for DirImpl {
         Ok(File { file })
     }
 
+    fn open_file_rw(&mut self, name: &str) -> Result<Self::ReadWrite, FSError> {
+        let path = self.path.join(name);
+        let mut opts = std::fs::OpenOptions::new();
+        let file = opts.read(true).write(true).open(path)?;
+        Ok(File { file })
+    }
+
     fn get_file_size(&mut self, name: &str) -> Result<u32, FSError> {
         let path = self.path.join(name);
         let meta = std::fs::metadata(path)?;
         Ok(meta.len() as u32)
     }
 
+    fn metadata(&mut self, name: &str) -> Result<Metadata, FSError> {
+        let path = self.path.join(name);
+        let meta = std::fs::metadata(path)?;
+        Ok(convert_metadata(&meta))
+    }
+
     fn remove_file(&mut self, name: &str) -> Result<(), FSError> {
         let path = self.path.join(name);
         let res = std::fs::remove_file(path);
@@ -234,6 +459,35 @@ impl Dir for DirImpl {
         }
     }
 
+    fn rename_file(&mut self, from: &str, to: &str) -> Result<(), FSError> {
+        let from = self.path.join(from);
+        let to = self.path.join(to);
+        std::fs::rename(from, to)?;
+        Ok(())
+    }
+
+    fn replace_file(&mut self, name: &str, data: &[u8]) -> Result<(), FSError> {
+        if name == crate::shared::TMP_FILE_NAME {
+            return Err(FSError::InvalidInput);
+        }
+        let tmp = self.path.join(crate::shared::TMP_FILE_NAME);
+        // Without syncing, the OS may write the rename to the disk before the data,
+        // and a power loss would leave the target file empty.
+        let res = std::fs::File::create(&tmp)
+            .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+            .and_then(|_| std::fs::rename(&tmp, self.path.join(name)));
+        if let Err(err) = res {
+            _ = std::fs::remove_file(&tmp);
+            return Err(err.into());
+        }
+        // Make the rename itself durable.
+        #[cfg(unix)]
+        if let Ok(dir) = std::fs::File::open(&self.path) {
+            _ = dir.sync_all();
+        }
+        Ok(())
+    }
+
     fn create_dir(&mut self, name: &str) -> Result<(), FSError> {
         let path = self.path.join(name);
         std::fs::create_dir(path)?;
@@ -254,26 +508,48 @@ impl Dir for DirImpl {
     fn iter_dir<F>(&mut self, mut f: F) -> Result<(), FSError>
     where
         F: FnMut(EntryKind, &[u8]),
+    {
+        self.iter_dir_meta(|meta, name| f(meta.kind, name))
+    }
+
+    fn iter_dir_meta<F>(&mut self, mut f: F) -> Result<(), FSError>
+    where
+        F: FnMut(&Metadata, &[u8]),
     {
         let entries = std::fs::read_dir(&self.path)?;
         for entry in entries {
             let entry = entry?;
-            let path = entry.path();
-            let kind = if path.is_dir() {
-                EntryKind::Dir
-            } else if path.is_file() {
-                EntryKind::File
-            } else {
+            // Follow symlinks, the same as `Path::is_dir` does.
+            let Ok(meta) = std::fs::metadata(entry.path()) else {
                 continue;
             };
+            if !meta.is_dir() && !meta.is_file() {
+                continue;
+            }
             let fname = entry.file_name();
             let fname = fname.as_encoded_bytes();
-            f(kind, fname);
+            f(&convert_metadata(&meta), fname);
         }
         Ok(())
     }
 }
 
+fn convert_metadata(meta: &std::fs::Metadata) -> Metadata {
+    let kind = if meta.is_dir() {
+        EntryKind::Dir
+    } else {
+        EntryKind::File
+    };
+    let size = if meta.is_dir() { 0 } else { meta.len() as u32 };
+    let modified = meta.modified().ok();
+    let modified = modified.and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+    Metadata {
+        kind,
+        size,
+        modified: modified.map(|d| DateTime::from_unix(d.as_secs())),
+    }
+}
+
 pub struct File {
     file: std::fs::File,
 }
@@ -311,10 +587,19 @@ impl embedded_io::Write for File {
 
 pub struct NetworkImpl<'a> {
     worker: Cell<Option<UdpWorker>>,
-    r_in: mpsc::Receiver<NetMessage>,
-    s_out: mpsc::Sender<NetMessage>,
-    s_stop: mpsc::Sender<()>,
+    r_in: mpsc::Receiver<NetworkResult<NetMessage>>,
+    s_out: mpsc::Sender<NetEvent>,
     local_addr: Option<SocketAddr>,
+    /// The delivery status of the latest message sent to each peer.
+    statuses: SendStatuses,
+    /// Peers discovered by their advertisements.
+    peers: Peers,
+    /// The encoded [Advertisement] to send in [Network::net_advertise].
+    advertisement: Box<[u8]>,
+    /// The key set by [Network::net_set_key]. Passed to the worker when it starts.
+    key: Option<SessionKey>,
+    /// The worker thread. None if not started.
+    thread: Option<std::thread::JoinHandle<()>>,
     _life: &'a PhantomData<()>,
 }
 
@@ -322,21 +607,56 @@ impl<'a> NetworkImpl<'a> {
     fn new() -> Self {
         let (s_in, r_in) = mpsc::channel();
         let (s_out, r_out) = mpsc::channel();
-        let (s_stop, r_stop) = mpsc::channel();
+        let statuses = SendStatuses::default();
+        let peers = Peers::default();
         let worker = Cell::new(Some(UdpWorker {
             s_in,
-            r_out,
-            r_stop,
+            r_events: r_out,
+            s_events: s_out.clone(),
+            statuses: statuses.clone(),
+            peers: peers.clone(),
         }));
+        let advertisement = postcard::to_allocvec(&Advertisement::default()).unwrap();
         Self {
             worker,
             r_in,
             s_out,
-            s_stop,
             local_addr: None,
+            statuses,
+            peers,
+            advertisement: advertisement.into_boxed_slice(),
+            key: None,
+            thread: None,
             _life: &PhantomData,
         }
     }
+
+    /// Stop the worker thread and wait for it to finish.
+    fn stop(&mut self) -> NetworkResult<()> {
+        _ = self.s_out.send(NetEvent::Stop);
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            return Err(NetworkError::NetThreadPanicked);
+        }
+        Ok(())
+    }
+
+    /// The error to report when the worker thread is not running anymore.
+    fn dead_worker_error(&mut self) -> NetworkError {
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            return NetworkError::NetThreadPanicked;
+        }
+        NetworkError::NetThreadDeallocated
+    }
+}
+
+impl Drop for NetworkImpl<'_> {
+    fn drop(&mut self) {
+        _ = self.s_out.send(NetEvent::Stop);
+    }
 }
 
 pub type Addr = SocketAddr;
@@ -349,62 +669,159 @@ impl<'a> Network for DeviceImpl<'a> {
     }
 
     fn net_start(&mut self) -> NetworkResult<()> {
+        if self.config.max_message_size > MAX_MESSAGE_SIZE_LIMIT {
+            eprintln!(
+                "WARNING: max message size must be at most {MAX_MESSAGE_SIZE_LIMIT} bytes, got {}",
+                self.config.max_message_size
+            );
+            return Err(NetworkError::Error("max message size is too big for UDP"));
+        }
         let worker = self.network.worker.replace(None);
         let Some(worker) = worker else {
             return Ok(());
         };
-        let local_addr = worker.start(self.config.udp_ip)?;
+        let (local_addr, thread) = worker.start(&self.config, self.network.key.as_ref())?;
         self.network.local_addr = Some(local_addr);
+        self.network.thread = Some(thread);
         Ok(())
     }
 
     fn net_stop(&mut self) -> NetworkResult<()> {
-        _ = self.network.s_stop.send(());
+        let res = self.network.stop();
+        let advertisement = core::mem::take(&mut self.network.advertisement);
+        let key = self.network.key.take();
         self.network = NetworkImpl::new();
-        Ok(())
+        self.network.advertisement = advertisement;
+        self.network.key = key;
+        res
     }
 
     fn net_advertise(&mut self) -> NetworkResult<()> {
-        let hello = b"HELLO".to_owned();
-        let hello: Box<[u8]> = Box::new(hello);
-        for ip in &self.config.peers {
+        let mut ips = self.config.peers.clone();
+        match self.config.discovery {
+            Discovery::Peers => {}
+            Discovery::Broadcast => ips.push(broadcast_ip(self.config.udp_ip)),
+            Discovery::Multicast(group) => ips.push(group),
+        }
+        // The socket cannot send packets to addresses of a different IP version.
+        ips.retain(|ip| ip.is_ipv4() == self.config.udp_ip.is_ipv4());
+        // Emulators on this machine are discovered through the LAN as well.
+        // Advertising to localhost too would make them see us twice.
+        if !matches!(self.config.discovery, Discovery::Peers) {
+            ips.retain(|ip| !ip.is_loopback());
+        }
+        for ip in ips {
             for port in UDP_PORT_MIN..=UDP_PORT_MAX {
-                let addr = SocketAddr::new(*ip, port);
-                let res = self.network.s_out.send((addr, hello.clone()));
+                let addr = SocketAddr::new(ip, port);
+                let cmd = NetEvent::Advertise(addr, self.network.advertisement.clone());
+                let res = self.network.s_out.send(cmd);
                 if res.is_err() {
-                    return Err(NetworkError::NetThreadDeallocated);
+                    return Err(self.network.dead_worker_error());
                 }
             }
         }
         Ok(())
     }
 
+    fn net_set_advertisement(&mut self, ad: Advertisement) -> NetworkResult<()> {
+        let raw = postcard::to_allocvec(&ad)?;
+        if raw.len() > self.config.max_message_size {
+            return Err(NetworkError::OutMessageTooBig);
+        }
+        self.network.advertisement = raw.into_boxed_slice();
+        Ok(())
+    }
+
+    fn net_peers(&mut self) -> NetworkResult<Vec<Peer<Self::Addr>>> {
+        let Ok(peers) = self.network.peers.0.lock() else {
+            return Err(NetworkError::NetThreadPanicked);
+        };
+        let peers = peers.iter().map(|(addr, peer)| {
+            let last_seen = peer.seen_at.saturating_duration_since(self.start);
+            Peer {
+                addr: *addr,
+                advertisement: peer.advertisement.clone(),
+                last_seen: Instant {
+                    us: last_seen.as_micros() as u64,
+                },
+            }
+        });
+        Ok(peers.collect())
+    }
+
+    fn net_set_key(&mut self, key: Option<SessionKey>) -> NetworkResult<()> {
+        self.network.key = key.clone();
+        if self.network.thread.is_none() {
+            return Ok(());
+        }
+        let res = self.network.s_out.send(NetEvent::SetKey(key));
+        if res.is_err() {
+            return Err(self.network.dead_worker_error());
+        }
+        Ok(())
+    }
+
     fn net_recv(&mut self) -> NetworkResult<Option<(Self::Addr, Box<[u8]>)>> {
-        Ok(self.network.r_in.try_recv().ok())
+        match self.network.r_in.try_recv() {
+            Ok(Ok(msg)) => Ok(Some(msg)),
+            Ok(Err(err)) => Err(err),
+            Err(mpsc::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::TryRecvError::Disconnected) => Err(self.network.dead_worker_error()),
+        }
     }
 
     fn net_send(&mut self, addr: Self::Addr, data: &[u8]) -> NetworkResult<()> {
-        if data.len() >= 200 {
+        if data.len() > self.config.max_message_size {
             return Err(NetworkError::OutMessageTooBig);
         };
+        if self.network.local_addr == Some(addr) {
+            return Ok(());
+        }
+        // Set before sending, so that it doesn't override the status set by the worker.
+        self.network.statuses.set(addr, SendStatus::Sending(0));
+        let msg = data.to_vec().into_boxed_slice();
+        let res = self.network.s_out.send(NetEvent::Send(addr, msg));
+        if res.is_err() {
+            return Err(self.network.dead_worker_error());
+        }
+        Ok(())
+    }
+
+    fn net_send_reliable(&mut self, addr: Self::Addr, data: &[u8]) -> NetworkResult<()> {
+        if data.len() > self.config.max_message_size {
+            return Err(NetworkError::OutMessageTooBig);
+        };
+        if self.network.local_addr == Some(addr) {
+            return Ok(());
+        }
         let msg = data.to_vec().into_boxed_slice();
-        let res = self.network.s_out.send((addr, msg));
+        let res = self.network.s_out.send(NetEvent::SendReliable(addr, msg));
         if res.is_err() {
-            return Err(NetworkError::NetThreadDeallocated);
+            return Err(self.network.dead_worker_error());
         }
         Ok(())
     }
 
-    fn net_send_status(&mut self, _: Self::Addr) -> NetworkResult<firefly_types::spi::SendStatus> {
-        Ok(firefly_types::spi::SendStatus::Empty)
+    fn net_max_message_size(&self) -> usize {
+        self.config.max_message_size
+    }
+
+    fn net_send_status(&mut self, addr: Self::Addr) -> NetworkResult<SendStatus> {
+        Ok(self.network.statuses.get(addr))
     }
 }
 
 pub struct SerialImpl {
-    worker: Cell<Option<TcpWorker>>,
-    r_in: mpsc::Receiver<SerialMessage>,
-    s_out: mpsc::Sender<SerialMessage>,
+    worker: Cell<Option<SerialWorker>>,
+    r_in: mpsc::Receiver<NetworkResult<SerialEvent>>,
+    s_out: mpsc::Sender<(Option<ClientId>, SerialMessage)>,
     s_stop: mpsc::Sender<()>,
+    /// The largest number of bytes waiting to be written into a connection.
+    queued: Arc<AtomicUsize>,
+    /// The number of messages dropped because a connection queue was full.
+    dropped: Arc<AtomicUsize>,
+    /// The worker thread. None if not started.
+    thread: Option<std::thread::JoinHandle<()>>,
 }
 
 impl SerialImpl {
@@ -412,10 +829,14 @@ impl SerialImpl {
         let (s_in, r_in) = mpsc::channel();
         let (s_out, r_out) = mpsc::channel();
         let (s_stop, r_stop) = mpsc::channel();
-        let worker = TcpWorker {
+        let queued = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let worker = SerialWorker {
             s_in,
             r_out,
             r_stop,
+            queued: queued.clone(),
+            dropped: dropped.clone(),
         };
         let worker = Cell::new(Some(worker));
         Self {
@@ -423,7 +844,31 @@ impl SerialImpl {
             r_in,
             s_out,
             s_stop,
+            queued,
+            dropped,
+            thread: None,
+        }
+    }
+
+    /// Stop the worker thread and wait for it to finish.
+    fn stop(&mut self) -> NetworkResult<()> {
+        _ = self.s_stop.send(());
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            return Err(NetworkError::NetThreadPanicked);
+        }
+        Ok(())
+    }
+
+    /// The error to report when the worker thread is not running anymore.
+    fn dead_worker_error(&mut self) -> NetworkError {
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            return NetworkError::NetThreadPanicked;
         }
+        NetworkError::NetThreadDeallocated
     }
 }
 
@@ -433,30 +878,48 @@ impl Serial for DeviceImpl<'_> {
         let Some(worker) = worker else {
             return Ok(());
         };
-        worker.start(self.config.tcp_ip)?;
+        let listener = match &self.config.serial_socket {
+            Some(path) => Listener::bind_unix(path)?,
+            None => Listener::bind_tcp(self.config.tcp_ip)?,
+        };
+        let thread = worker.start(listener);
+        self.serial.thread = Some(thread);
         Ok(())
     }
 
     fn serial_stop(&mut self) -> NetworkResult<()> {
-        _ = self.serial.s_stop.send(());
-        Ok(())
+        let res = self.serial.stop();
+        self.serial = SerialImpl::new();
+        res
     }
 
-    fn serial_recv(&mut self) -> NetworkResult<Option<Box<[u8]>>> {
-        Ok(self.serial.r_in.try_recv().ok())
+    fn serial_recv_event(&mut self) -> NetworkResult<Option<SerialEvent>> {
+        match self.serial.r_in.try_recv() {
+            Ok(msg) => msg.map(Some),
+            Err(mpsc::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::TryRecvError::Disconnected) => Err(self.serial.dead_worker_error()),
+        }
     }
 
-    fn serial_send(&mut self, data: &[u8]) -> NetworkResult<()> {
-        if data.len() >= 200 {
+    fn serial_send_to(&mut self, client: Option<ClientId>, data: &[u8]) -> NetworkResult<()> {
+        if data.len() > framing::SERIAL_MAX_SIZE {
             return Err(NetworkError::OutMessageTooBig);
         };
-        let msg = data.to_vec().into_boxed_slice();
-        let res = self.serial.s_out.send(msg);
+        let msg = framing::encode(data).into_boxed_slice();
+        let res = self.serial.s_out.send((client, msg));
         if res.is_err() {
-            return Err(NetworkError::NetThreadDeallocated);
+            return Err(self.serial.dead_worker_error());
         }
         Ok(())
     }
+
+    fn serial_queued(&self) -> usize {
+        self.serial.queued.load(Ordering::Relaxed)
+    }
+
+    fn serial_dropped(&self) -> usize {
+        self.serial.dropped.load(Ordering::Relaxed)
+    }
 }
 
 impl Wifi for DeviceImpl<'_> {
@@ -491,17 +954,11 @@ impl Wifi for DeviceImpl<'_> {
         Ok(())
     }
 
-    fn tcp_connect(&mut self, ip: u32, port: u16) -> NetworkResult<()> {
+    fn tcp_connect(&mut self, ip: IpAddr, port: u16) -> NetworkResult<()> {
         if self.wifi_status != 4 {
             return Err(NetworkError::Error("not connected to wifi"));
         }
-        let ip = Ipv4Addr::new(
-            (ip >> 24) as u8,
-            (ip >> 16) as u8,
-            (ip >> 8) as u8,
-            ip as u8,
-        );
-        let addr = SocketAddrV4::new(ip, port);
+        let addr = SocketAddr::new(ip, port);
         let Ok(stream) = TcpStream::connect(addr) else {
             return Err(NetworkError::CannotBind);
         };
@@ -562,135 +1019,1059 @@ impl Wifi for DeviceImpl<'_> {
 
 type NetMessage = (SocketAddr, Box<[u8]>);
 type SerialMessage = Box<[u8]>;
+/// The local address and the handle of the started UDP worker thread.
+type WorkerResult = Result<(SocketAddr, std::thread::JoinHandle<()>), NetworkError>;
+
+/// The time to send the packet, the counter to keep the packets
+/// with the same send time in order, the destination, and the packet itself.
+type DelayedPacket = (std::time::Instant, u64, SocketAddr, Vec<u8>);
+
+/// An event for the UDP worker thread to handle.
+enum NetEvent {
+    /// Send the advertisement to the given address. Not acknowledged.
+    Advertise(SocketAddr, Box<[u8]>),
+    /// Queue the message to be sent to the given peer.
+    Send(SocketAddr, Box<[u8]>),
+    /// Queue the message to be sent to the given peer with guaranteed ordered delivery.
+    SendReliable(SocketAddr, Box<[u8]>),
+    /// A packet received from the given address.
+    Received(SocketAddr, Box<[u8]>),
+    /// A packet was received but it didn't fit into the buffer.
+    TooBig,
+    /// Authenticate all the following packets with the given key.
+    SetKey(Option<SessionKey>),
+    /// Stop the worker.
+    Stop,
+    /// The receiving thread has exited. Sent by [ReceiverGuard].
+    ReceiverStopped,
+}
+
+/// A datagram sent between emulators.
+#[derive(Serialize, Deserialize)]
+enum Packet<'a> {
+    /// Device presence advertisement: the random ID of the sender
+    /// and encoded [Advertisement].
+    ///
+    /// The ID lets the sender detect its own broadcast and multicast advertisements.
+    Advertise(u32, &'a [u8]),
+    /// A message with its sequence number. Must be acknowledged.
+    Data(u32, &'a [u8]),
+    /// Acknowledgement of the message with the given sequence number.
+    Ack(u32),
+    /// Encoded [reliable::Frame].
+    Reliable(&'a [u8]),
+    /// Another encoded packet authenticated with the session key.
+    ///
+    /// If encrypted, the data is the encrypted packet followed by the tag.
+    /// Otherwise, the packet is sent as is and followed by the tag.
+    Sealed {
+        encrypted: bool,
+        nonce: [u8; 12],
+        data: &'a [u8],
+    },
+}
+
+/// Delivery status of the latest message sent to each peer.
+///
+/// Shared between the device and the UDP worker thread.
+#[derive(Clone, Default)]
+struct SendStatuses(Arc<Mutex<HashMap<SocketAddr, SendStatus>>>);
+
+impl SendStatuses {
+    fn get(&self, addr: SocketAddr) -> SendStatus {
+        let Ok(statuses) = self.0.lock() else {
+            return SendStatus::Empty;
+        };
+        statuses.get(&addr).copied().unwrap_or(SendStatus::Empty)
+    }
+
+    fn set(&self, addr: SocketAddr, status: SendStatus) {
+        if let Ok(mut statuses) = self.0.lock() {
+            statuses.insert(addr, status);
+        }
+    }
+}
+
+/// Peers discovered by their advertisements.
+///
+/// Shared between the device and the UDP worker thread.
+#[derive(Clone, Default)]
+struct Peers(Arc<Mutex<HashMap<SocketAddr, SeenPeer>>>);
+
+struct SeenPeer {
+    /// The random ID of the peer's UDP worker.
+    id: u32,
+    advertisement: Advertisement,
+    /// When the latest advertisement from the peer was received.
+    seen_at: std::time::Instant,
+}
+
+/// A message sent to a peer and not acknowledged yet.
+struct OutMessage {
+    seq: u32,
+    data: Box<[u8]>,
+    /// How many times the message was sent.
+    attempts: u8,
+    /// When the message was sent the last time.
+    sent_at: Option<std::time::Instant>,
+}
+
+/// The queue of outgoing messages to a single peer.
+///
+/// Every message is sent right away with its own sequence number
+/// and then sent again until acknowledged or until it runs out of attempts.
+/// The queue is bounded, so nothing piles up if the peer is slow or gone:
+/// when it's full, the oldest message is not retried anymore.
+/// [Network::net_send_status] reports the delivery of the latest message.
+struct Outbox {
+    pending: VecDeque<OutMessage>,
+    /// The sequence number of the latest message.
+    seq: u32,
+}
+
+impl Outbox {
+    fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+            // Start after all sequence numbers used before the restart,
+            // so that the peer doesn't mistake the new messages for duplicates.
+            seq: new_session(),
+        }
+    }
+
+    /// Queue the message, dropping the oldest one if the queue is full.
+    fn push(&mut self, data: Box<[u8]>) {
+        self.seq = self.seq.wrapping_add(1);
+        if self.pending.len() >= OUTBOX_SIZE {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(OutMessage {
+            seq: self.seq,
+            data,
+            attempts: 0,
+            sent_at: None,
+        });
+    }
+}
+
+/// The sequence numbers of the recent messages received from a single peer.
+///
+/// Lets through messages that arrive out of order
+/// but drops duplicates and messages that are too old.
+struct SeqWindow {
+    /// The highest sequence number received so far.
+    latest: u32,
+    /// The bit N is set if the message `latest - N` was received.
+    received: u64,
+}
+
+impl SeqWindow {
+    fn new(seq: u32) -> Self {
+        Self {
+            latest: seq,
+            received: 1,
+        }
+    }
+
+    /// Remember the sequence number. Returns false if the message should be dropped.
+    fn insert(&mut self, seq: u32) -> bool {
+        let ahead = seq.wrapping_sub(self.latest);
+        if ahead != 0 && (ahead as i32) > 0 {
+            self.received = self.received.checked_shl(ahead).unwrap_or(0) | 1;
+            self.latest = seq;
+            return true;
+        }
+        let behind = self.latest.wrapping_sub(seq);
+        if behind >= u64::BITS {
+            return false;
+        }
+        let bit = 1 << behind;
+        let is_new = self.received & bit == 0;
+        self.received |= bit;
+        is_new
+    }
+}
 
 struct UdpWorker {
-    s_in: mpsc::Sender<NetMessage>,
-    r_out: mpsc::Receiver<NetMessage>,
-    r_stop: mpsc::Receiver<()>,
+    s_in: mpsc::Sender<NetworkResult<NetMessage>>,
+    /// Events from the device and from the receiving thread.
+    r_events: mpsc::Receiver<NetEvent>,
+    /// Passed into the receiving thread.
+    s_events: mpsc::Sender<NetEvent>,
+    statuses: SendStatuses,
+    peers: Peers,
 }
 
 impl UdpWorker {
-    fn start(self, ip: IpAddr) -> Result<SocketAddr, NetworkError> {
+    /// Bind the socket and start the worker thread.
+    ///
+    /// The key is applied before any packet is received.
+    fn start(self, config: &DeviceConfig, key: Option<&SessionKey>) -> WorkerResult {
+        // Sockets bound to a specific address don't receive broadcast
+        // and multicast packets.
+        let bind_ip = match (config.discovery.clone(), config.udp_ip) {
+            (Discovery::Peers, ip) => ip,
+            (_, IpAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (_, IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
         let addrs: Vec<_> = (UDP_PORT_MIN..=UDP_PORT_MAX)
-            .map(|port| SocketAddr::new(ip, port))
+            .map(|port| SocketAddr::new(bind_ip, port))
             .collect();
         let socket = match UdpSocket::bind(&addrs[..]) {
             Ok(socket) => socket,
             Err(_) => return Err(NetworkError::CannotBind),
         };
-        let timeout = std::time::Duration::from_millis(10);
+        // Loop is needed to discover emulators running on the same machine.
+        let res = match (config.discovery.clone(), config.udp_ip) {
+            (Discovery::Peers, _) => Ok(()),
+            (Discovery::Broadcast, IpAddr::V4(_)) => socket.set_broadcast(true),
+            (Discovery::Broadcast, IpAddr::V6(_)) => socket.set_multicast_loop_v6(true),
+            (Discovery::Multicast(IpAddr::V4(group)), IpAddr::V4(ip)) => {
+                let interface = if ip.is_loopback() {
+                    Ipv4Addr::UNSPECIFIED
+                } else {
+                    ip
+                };
+                socket
+                    .join_multicast_v4(&group, &interface)
+                    .and_then(|_| socket.set_multicast_loop_v4(true))
+            }
+            (Discovery::Multicast(IpAddr::V6(group)), IpAddr::V6(_)) => socket
+                .join_multicast_v6(&group, 0)
+                .and_then(|_| socket.set_multicast_loop_v6(true)),
+            (Discovery::Multicast(_), _) => return Err(NetworkError::CannotBind),
+        };
+        if res.is_err() {
+            return Err(NetworkError::CannotBind);
+        }
+        // The timeout only limits how long it takes for the receiving thread to stop.
+        let timeout = std::time::Duration::from_millis(100);
         socket.set_read_timeout(Some(timeout)).unwrap();
         if let Ok(addr) = socket.local_addr() {
             println!("listening on {addr}/udp");
         } else {
             println!("listening a UDP port");
         }
-        let local_addr = socket.local_addr().unwrap();
-        std::thread::spawn(move || {
-            loop {
-                match self.r_stop.try_recv() {
-                    Ok(_) | Err(mpsc::TryRecvError::Disconnected) => {
-                        break;
-                    }
-                    Err(mpsc::TryRecvError::Empty) => {}
-                }
-                let mut buf = vec![0; 64];
-                if let Ok((size, addr)) = socket.recv_from(&mut buf) {
-                    if size == 0 {
-                        continue;
-                    }
-                    buf.truncate(size);
-                    let buf = buf.into_boxed_slice();
-                    _ = self.s_in.send((addr, buf));
-                }
-                if let Ok((addr, buf)) = self.r_out.try_recv() {
-                    if addr == local_addr {
-                        continue;
-                    }
-                    _ = socket.send_to(&buf, addr);
-                }
+        let mut local_addr = socket.local_addr().unwrap();
+        if local_addr.ip().is_unspecified() {
+            local_addr.set_ip(lan_ip(config.udp_ip));
+        }
+        let Ok(recv_socket) = socket.try_clone() else {
+            return Err(NetworkError::CannotBind);
+        };
+        let mut link = Link::new(socket, config.net_conditions.clone())?;
+        link.sealer = key.map(Sealer::new);
+        let running = Arc::new(AtomicBool::new(true));
+        let max_size = config.max_message_size;
+        let s_events = self.s_events.clone();
+        let recv_running = running.clone();
+        let receiver =
+            std::thread::spawn(move || receive(recv_socket, s_events, recv_running, max_size));
+        let state = UdpState {
+            id: rand::random(),
+            link,
+            local_addr,
+            max_size,
+            started: std::time::Instant::now(),
+            outboxes: HashMap::new(),
+            channels: HashMap::new(),
+            seq_windows: HashMap::new(),
+        };
+        let thread = std::thread::spawn(move || {
+            self.run(state);
+            running.store(false, Ordering::Relaxed);
+            // Propagate the panic so that joining the worker reports it.
+            if let Err(err) = receiver.join() {
+                std::panic::resume_unwind(err);
             }
         });
-        Ok(local_addr)
+        Ok((local_addr, thread))
     }
-}
 
-struct TcpWorker {
-    s_in: mpsc::Sender<SerialMessage>,
-    r_out: mpsc::Receiver<SerialMessage>,
-    r_stop: mpsc::Receiver<()>,
-}
+    /// Handle events until stopped.
+    ///
+    /// The thread sleeps until there is an event to handle
+    /// or a packet to send or retransmit.
+    fn run(self, mut state: UdpState) {
+        loop {
+            let res = match state.next_wakeup() {
+                Some(wakeup) => {
+                    let timeout = wakeup.saturating_duration_since(std::time::Instant::now());
+                    self.r_events.recv_timeout(timeout)
+                }
+                None => self.r_events.recv().map_err(Into::into),
+            };
+            match res {
+                Ok(NetEvent::Stop | NetEvent::ReceiverStopped)
+                | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Ok(event) => self.handle(&mut state, event),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+            self.flush(&mut state);
+        }
+    }
 
-impl TcpWorker {
-    fn start(self, ip: IpAddr) -> Result<(), NetworkError> {
-        let addrs: Vec<_> = (TCP_PORT_MIN..=TCP_PORT_MAX)
-            .map(|port| SocketAddr::new(ip, port))
-            .collect();
-        let Ok(socket) = TcpListener::bind(&addrs[..]) else {
-            return Err(NetworkError::CannotBind);
-        };
-        socket.set_nonblocking(true).unwrap();
-        std::thread::spawn(move || {
-            let mut streams = RingBuf::new();
-            loop {
-                match self.r_stop.try_recv() {
-                    Ok(_) | Err(mpsc::TryRecvError::Disconnected) => {
-                        break;
-                    }
-                    Err(mpsc::TryRecvError::Empty) => {}
+    fn handle(&self, state: &mut UdpState, event: NetEvent) {
+        match event {
+            NetEvent::Advertise(addr, data) => {
+                if addr != state.local_addr {
+                    state.link.send(addr, &Packet::Advertise(state.id, &data));
                 }
+            }
+            NetEvent::Send(addr, data) => {
+                let outbox = state.outboxes.entry(addr).or_insert_with(Outbox::new);
+                outbox.push(data);
+            }
+            NetEvent::SendReliable(addr, data) => {
+                let channel = state.channels.entry(addr).or_insert_with(new_channel);
+                channel.send(data);
+            }
+            NetEvent::Received(addr, raw) => {
+                self.handle_packet(state, addr, &raw);
+            }
+            NetEvent::TooBig => {
+                _ = self.s_in.send(Err(NetworkError::InMessageTooBig));
+            }
+            NetEvent::SetKey(key) => {
+                state.link.sealer = key.as_ref().map(Sealer::new);
+            }
+            NetEvent::Stop | NetEvent::ReceiverStopped => {}
+        }
+    }
 
-                if let Ok((stream, _addr)) = socket.accept() {
-                    stream.set_nonblocking(true).unwrap();
-                    streams.push(stream);
+    fn handle_packet(&self, state: &mut UdpState, addr: SocketAddr, raw: &[u8]) {
+        let Ok(packet) = postcard::from_bytes(raw) else {
+            return;
+        };
+        let opened;
+        let packet = match (&state.link.sealer, packet) {
+            (_, packet @ Packet::Advertise(..)) => packet,
+            (
+                Some(sealer),
+                Packet::Sealed {
+                    encrypted,
+                    nonce,
+                    data,
+                },
+            ) => {
+                let Some(raw) = sealer.open(encrypted, &nonce, data) else {
+                    return;
+                };
+                opened = raw;
+                let Ok(packet) = postcard::from_bytes(&opened) else {
+                    return;
                 };
+                if matches!(packet, Packet::Advertise(..) | Packet::Sealed { .. }) {
+                    return;
+                }
+                packet
+            }
+            // Cannot verify a sealed packet without the key.
+            (None, Packet::Sealed { .. }) => return,
+            (None, packet) => packet,
+            // Unauthenticated packet when the key is set.
+            (Some(_), _) => return,
+        };
+        self.handle_trusted(state, addr, packet);
+    }
 
-                for stream in streams.iter_mut() {
-                    let mut buf = vec![0; 200];
-                    let Ok(size) = stream.read(&mut buf) else {
-                        continue;
-                    };
-                    if size == 0 {
-                        continue;
-                    }
-                    buf.truncate(size);
-                    let buf = buf.into_boxed_slice();
-                    _ = self.s_in.send(buf);
+    /// Handle a packet that is authenticated or doesn't need to be.
+    fn handle_trusted(&self, state: &mut UdpState, addr: SocketAddr, packet: Packet<'_>) {
+        match packet {
+            Packet::Advertise(id, data) => {
+                if id == state.id {
+                    return;
                 }
-                if let Ok(buf) = self.r_out.try_recv() {
-                    for stream in streams.iter_mut() {
-                        _ = stream.write_all(&buf)
+                let Ok(advertisement) = postcard::from_bytes(data) else {
+                    return;
+                };
+                let Ok(mut peers) = self.peers.0.lock() else {
+                    return;
+                };
+                // The same emulator might be reachable by several addresses,
+                // like localhost and the LAN IP. Keep the first one it was seen at.
+                let known = peers.iter().find(|(_, peer)| peer.id == id);
+                let addr = known.map_or(addr, |(addr, _)| *addr);
+                let peer = SeenPeer {
+                    id,
+                    advertisement,
+                    seen_at: std::time::Instant::now(),
+                };
+                peers.insert(addr, peer);
+                drop(peers);
+                // Advertisements aren't authenticated, so when the session key is set,
+                // anyone could inject this message into the session.
+                if state.link.sealer.is_some() {
+                    return;
+                }
+                // The runtime detects new peers by this message,
+                // the same way as on the device.
+                _ = self.s_in.send(Ok((addr, Box::new(*b"HELLO"))));
+            }
+            Packet::Data(seq, data) => {
+                if data.len() > state.max_size {
+                    _ = self.s_in.send(Err(NetworkError::InMessageTooBig));
+                    return;
+                }
+                state.link.send(addr, &Packet::Ack(seq));
+                // If the ack was lost, the peer sends the same message again.
+                // Or, someone replays a message sealed with the session key.
+                let is_new = match state.seq_windows.entry(addr) {
+                    Entry::Occupied(mut entry) => entry.get_mut().insert(seq),
+                    Entry::Vacant(entry) => {
+                        entry.insert(SeqWindow::new(seq));
+                        true
                     }
+                };
+                if is_new {
+                    _ = self.s_in.send(Ok((addr, data.into())));
                 }
             }
-        });
-        Ok(())
+            Packet::Ack(seq) => {
+                if let Some(outbox) = state.outboxes.get_mut(&addr) {
+                    self.ack(addr, outbox, seq);
+                }
+            }
+            Packet::Reliable(raw) => {
+                let Some(frame) = reliable::Frame::decode(raw) else {
+                    return;
+                };
+                if let reliable::Frame::Data { data, .. } = frame
+                    && data.len() > state.max_size
+                {
+                    _ = self.s_in.send(Err(NetworkError::InMessageTooBig));
+                    return;
+                }
+                let channel = state.channels.entry(addr).or_insert_with(new_channel);
+                if let Some(ack) = channel.handle(frame) {
+                    state.link.send(addr, &Packet::Reliable(&ack.encode()));
+                }
+                while let Some(data) = channel.recv() {
+                    _ = self.s_in.send(Ok((addr, data)));
+                }
+            }
+            Packet::Sealed { .. } => {}
+        }
+    }
+
+    /// Send all packets that are due.
+    fn flush(&self, state: &mut UdpState) {
+        state.link.flush();
+        for (addr, outbox) in &mut state.outboxes {
+            self.flush_outbox(&mut state.link, *addr, outbox);
+        }
+        let now = state.now();
+        let link = &mut state.link;
+        for (addr, channel) in &mut state.channels {
+            channel.poll(now, |frame| {
+                link.send(*addr, &Packet::Reliable(&frame.encode()));
+            });
+            // Keep the channel, so that it still knows the session of the peer.
+            if channel.is_broken() {
+                self.statuses.set(*addr, SendStatus::Failed);
+                channel.restart(new_session());
+            }
+        }
+    }
+
+    /// Handle acknowledgement of a message sent to the peer.
+    ///
+    /// The status is tracked only for the latest message.
+    fn ack(&self, addr: SocketAddr, outbox: &mut Outbox, seq: u32) {
+        let Some(idx) = outbox.pending.iter().position(|msg| msg.seq == seq) else {
+            return;
+        };
+        let Some(msg) = outbox.pending.remove(idx) else {
+            return;
+        };
+        if msg.seq == outbox.seq {
+            self.statuses.set(addr, SendStatus::Delivered(msg.attempts));
+        }
+    }
+
+    /// Send or resend the queued messages to the peer, if needed.
+    fn flush_outbox(&self, link: &mut Link, addr: SocketAddr, outbox: &mut Outbox) {
+        let now = std::time::Instant::now();
+        let latest = outbox.seq;
+        outbox.pending.retain_mut(|msg| {
+            if let Some(sent_at) = msg.sent_at
+                && now.duration_since(sent_at) < RETRY_TIMEOUT
+            {
+                return true;
+            }
+            if msg.attempts >= SEND_ATTEMPTS {
+                if msg.seq == latest {
+                    self.statuses.set(addr, SendStatus::Failed);
+                }
+                return false;
+            }
+            link.send(addr, &Packet::Data(msg.seq, &msg.data));
+            msg.attempts += 1;
+            msg.sent_at = Some(now);
+            if msg.seq == latest {
+                self.statuses.set(addr, SendStatus::Sending(msg.attempts));
+            }
+            true
+        });
+    }
+}
+
+/// The state of the UDP worker thread.
+struct UdpState {
+    /// The random ID of the worker sent in advertisements.
+    id: u32,
+    link: Link,
+    local_addr: SocketAddr,
+    max_size: usize,
+    /// The reference point for the time of [reliable::Channel].
+    started: std::time::Instant,
+    outboxes: HashMap<SocketAddr, Outbox>,
+    channels: HashMap<SocketAddr, reliable::Channel>,
+    /// The sequence numbers of the recent messages received from each peer.
+    seq_windows: HashMap<SocketAddr, SeqWindow>,
+}
+
+impl UdpState {
+    fn now(&self) -> Instant {
+        Instant {
+            us: self.started.elapsed().as_micros() as u64,
+        }
+    }
+
+    /// When the next packet has to be sent or retransmitted, if any.
+    fn next_wakeup(&self) -> Option<std::time::Instant> {
+        let delayed = self.link.next_send();
+        let pending = self.outboxes.values().flat_map(|outbox| &outbox.pending);
+        // New messages are sent right after they are pushed.
+        let outboxes = pending.filter_map(|msg| Some(msg.sent_at? + RETRY_TIMEOUT));
+        let channels = self.channels.values().filter_map(|channel| {
+            let at = channel.next_poll()?;
+            Some(self.started + core::time::Duration::from(at - Instant { us: 0 }))
+        });
+        delayed.into_iter().chain(outboxes).chain(channels).min()
+    }
+}
+
+/// Receive packets from the socket and pass them into the worker thread.
+fn receive(
+    socket: UdpSocket,
+    s_events: mpsc::Sender<NetEvent>,
+    running: Arc<AtomicBool>,
+    max_size: usize,
+) {
+    let _guard = ReceiverGuard(s_events.clone());
+    // One extra byte to detect messages that don't fit.
+    let mut buf = vec![0; max_size + PACKET_OVERHEAD + 1];
+    while running.load(Ordering::Relaxed) {
+        let Ok((size, addr)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        let event = if size == buf.len() {
+            NetEvent::TooBig
+        } else {
+            NetEvent::Received(addr, buf[..size].into())
+        };
+        if s_events.send(event).is_err() {
+            break;
+        }
+    }
+}
+
+/// Notifies the worker when the receiving thread exits, including on panic.
+///
+/// Without it, the worker would keep running without receiving anything.
+struct ReceiverGuard(mpsc::Sender<NetEvent>);
+
+impl Drop for ReceiverGuard {
+    fn drop(&mut self) {
+        _ = self.0.send(NetEvent::ReceiverStopped);
+    }
+}
+
+/// Authentication and encryption of packets with the session key.
+struct Sealer {
+    cipher: ChaCha20Poly1305,
+    encrypt: bool,
+}
+
+impl Sealer {
+    fn new(key: &SessionKey) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.key.into()),
+            encrypt: key.encrypt,
+        }
+    }
+
+    /// Wrap the encoded packet into encoded [Packet::Sealed].
+    fn seal(&self, raw: &[u8]) -> Option<Vec<u8>> {
+        let nonce: [u8; 12] = rand::random();
+        let data = if self.encrypt {
+            self.cipher.encrypt(&nonce.into(), raw).ok()?
+        } else {
+            // Encrypting an empty message produces only the tag
+            // that authenticates the associated data.
+            let payload = Payload { msg: &[], aad: raw };
+            let tag = self.cipher.encrypt(&nonce.into(), payload).ok()?;
+            [raw, &tag].concat()
+        };
+        let packet = Packet::Sealed {
+            encrypted: self.encrypt,
+            nonce,
+            data: &data,
+        };
+        postcard::to_allocvec(&packet).ok()
+    }
+
+    /// Verify and unwrap the encoded packet from [Packet::Sealed].
+    fn open(&self, encrypted: bool, nonce: &[u8; 12], data: &[u8]) -> Option<Vec<u8>> {
+        if encrypted != self.encrypt {
+            return None;
+        }
+        if encrypted {
+            return self.cipher.decrypt(nonce.into(), data).ok();
+        }
+        let msg_len = data.len().checked_sub(16)?;
+        let (msg, tag) = data.split_at(msg_len);
+        let payload = Payload { msg: tag, aad: msg };
+        self.cipher.decrypt(nonce.into(), payload).ok()?;
+        Some(msg.to_vec())
+    }
+}
+
+/// Get the IP address of the device in the local network.
+///
+/// If the given address is not a loopback, it is returned as is.
+/// Otherwise, the address of the interface with the default route is detected.
+/// Connecting a UDP socket doesn't send any packets, it only picks the route.
+fn lan_ip(ip: IpAddr) -> IpAddr {
+    if !ip.is_loopback() && !ip.is_unspecified() {
+        return ip;
+    }
+    let (unspecified, remote): (IpAddr, IpAddr) = match ip {
+        IpAddr::V4(_) => (
+            Ipv4Addr::UNSPECIFIED.into(),
+            Ipv4Addr::new(10, 255, 255, 255).into(),
+        ),
+        IpAddr::V6(_) => (
+            Ipv6Addr::UNSPECIFIED.into(),
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+        ),
+    };
+    let Ok(socket) = UdpSocket::bind((unspecified, 0)) else {
+        return ip;
+    };
+    if socket.connect((remote, 1)).is_err() {
+        return ip;
+    }
+    match socket.local_addr() {
+        Ok(addr) if !addr.ip().is_unspecified() => addr.ip(),
+        _ => ip,
+    }
+}
+
+/// The address to broadcast advertisements to in the given IP version.
+fn broadcast_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::BROADCAST.into(),
+        // All nodes on the local network segment.
+        IpAddr::V6(_) => Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1).into(),
+    }
+}
+
+fn new_channel() -> reliable::Channel {
+    reliable::Channel::new(new_session())
+}
+
+/// Get a number greater than all numbers returned before.
+///
+/// The number is the wall clock time in milliseconds, so it grows
+/// even across emulator restarts, but never repeats within the process.
+/// Used as the reliable channel session and as the first message sequence number.
+fn new_session() -> u32 {
+    static LAST_SESSION: AtomicU32 = AtomicU32::new(0);
+    let now = std::time::SystemTime::now();
+    let since_epoch = now.duration_since(std::time::UNIX_EPOCH);
+    let ms = since_epoch.unwrap_or_default().as_millis() as u32;
+    let mut session = ms;
+    _ = LAST_SESSION.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+        session = ms.max(last.wrapping_add(1));
+        Some(session)
+    });
+    session
+}
+
+/// The UDP socket with simulated network conditions for outgoing packets.
+struct Link {
+    socket: UdpSocket,
+    conditions: NetConditions,
+    /// A portable generator, so that the seed gives the same results everywhere.
+    rng: ChaCha8Rng,
+    /// Packets held back by the simulated latency, ordered by the time to send them.
+    delayed: BinaryHeap<Reverse<DelayedPacket>>,
+    counter: u64,
+    /// If set, all packets except advertisements are sealed with the session key.
+    sealer: Option<Sealer>,
+}
+
+impl Link {
+    /// Returns an error if a probability in the conditions is NaN.
+    fn new(socket: UdpSocket, mut conditions: NetConditions) -> NetworkResult<Self> {
+        for p in [
+            &mut conditions.loss,
+            &mut conditions.duplicate,
+            &mut conditions.reorder,
+        ] {
+            if p.is_nan() {
+                return Err(NetworkError::Error("network condition probability is NaN"));
+            }
+            *p = p.clamp(0., 1.);
+        }
+        let rng = ChaCha8Rng::seed_from_u64(conditions.seed);
+        Ok(Self {
+            socket,
+            conditions,
+            rng,
+            delayed: BinaryHeap::new(),
+            counter: 0,
+            sealer: None,
+        })
+    }
+
+    fn send(&mut self, addr: SocketAddr, packet: &Packet<'_>) {
+        let Ok(mut raw) = postcard::to_allocvec(packet) else {
+            return;
+        };
+        if let Some(sealer) = &self.sealer
+            && !matches!(packet, Packet::Advertise(..))
+        {
+            let Some(sealed) = sealer.seal(&raw) else {
+                return;
+            };
+            raw = sealed;
+        }
+        if self.conditions.is_ideal() {
+            _ = self.socket.send_to(&raw, addr);
+            return;
+        }
+        let conditions = &self.conditions;
+        if self.rng.random_bool(conditions.loss) {
+            return;
+        }
+        let mut copies = 1;
+        if self.rng.random_bool(conditions.duplicate) {
+            copies = 2;
+        }
+        let now = std::time::Instant::now();
+        for _ in 0..copies {
+            let mut delay = conditions.latency + conditions.jitter.mul_f64(self.rng.random());
+            if self.rng.random_bool(conditions.reorder) {
+                delay += REORDER_DELAY + conditions.jitter;
+            }
+            self.counter += 1;
+            let item = (now + delay, self.counter, addr, raw.clone());
+            self.delayed.push(Reverse(item));
+        }
+        self.flush();
+    }
+
+    /// When the next delayed packet is due, if any.
+    fn next_send(&self) -> Option<std::time::Instant> {
+        let Reverse((send_at, ..)) = self.delayed.peek()?;
+        Some(*send_at)
+    }
+
+    /// Send the delayed packets which are due.
+    fn flush(&mut self) {
+        let now = std::time::Instant::now();
+        while let Some(Reverse((send_at, ..))) = self.delayed.peek()
+            && *send_at <= now
+        {
+            let Some(Reverse((_, _, addr, raw))) = self.delayed.pop() else {
+                break;
+            };
+            _ = self.socket.send_to(&raw, addr);
+        }
+    }
+}
+
+struct SerialWorker {
+    s_in: mpsc::Sender<NetworkResult<SerialEvent>>,
+    r_out: mpsc::Receiver<(Option<ClientId>, SerialMessage)>,
+    r_stop: mpsc::Receiver<()>,
+    queued: Arc<AtomicUsize>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl SerialWorker {
+    fn start(self, socket: Listener) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut streams = RingBuf::new();
+            let mut next_id: ClientId = 0;
+            loop {
+                match self.r_stop.try_recv() {
+                    Ok(_) | Err(mpsc::TryRecvError::Disconnected) => {
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {}
+                }
+
+                if let Ok(stream) = socket.accept() {
+                    let id = next_id;
+                    next_id = next_id.wrapping_add(1);
+                    let conn = Connection {
+                        id,
+                        stream,
+                        frames: framing::Deframer::new(framing::SERIAL_MAX_SIZE),
+                        out: framing::FrameQueue::new(framing::SERIAL_QUEUE_SIZE),
+                        closed: false,
+                    };
+                    _ = self.s_in.send(Ok(SerialEvent::Connected(id)));
+                    if let Some(old) = streams.push(conn) {
+                        _ = self.s_in.send(Ok(SerialEvent::Disconnected(old.id)));
+                    }
+                };
+
+                let mut buf = [0; 200];
+                for conn in streams.iter_mut() {
+                    let size = match conn.stream.read(&mut buf) {
+                        Ok(0) => {
+                            conn.closed = true;
+                            continue;
+                        }
+                        Ok(size) => size,
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                        Err(_) => {
+                            conn.closed = true;
+                            continue;
+                        }
+                    };
+                    let id = conn.id;
+                    conn.frames.extend(&buf[..size], |msg| {
+                        let event = msg.map(|data| SerialEvent::Message(id, data));
+                        _ = self.s_in.send(event);
+                    });
+                }
+                streams.remove_closed(|conn| {
+                    _ = self.s_in.send(Ok(SerialEvent::Disconnected(conn.id)));
+                });
+
+                while let Ok((client, frame)) = self.r_out.try_recv() {
+                    let recipients = streams.iter_mut();
+                    let recipients =
+                        recipients.filter(|conn| client.is_none_or(|id| id == conn.id));
+                    let mut dropped = false;
+                    for conn in recipients {
+                        // Make room for the new frame, if the client reads fast enough.
+                        conn.flush();
+                        if !conn.out.push(&frame) {
+                            dropped = true;
+                        }
+                    }
+                    // A broadcast message is counted once,
+                    // no matter how many clients couldn't take it.
+                    if dropped {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                // The streams are non-blocking, so a slow client
+                // doesn't block the others. Whatever the stream doesn't accept
+                // stays in the queue until the next iteration.
+                let mut queued = 0;
+                for conn in streams.iter_mut() {
+                    conn.flush();
+                    queued = queued.max(conn.out.len());
+                }
+                self.queued.store(queued, Ordering::Relaxed);
+            }
+        })
+    }
+}
+
+/// A socket accepting serial clients.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Listen on the first free TCP port in the serial port range.
+    fn bind_tcp(ip: IpAddr) -> NetworkResult<Self> {
+        let addrs: Vec<_> = (TCP_PORT_MIN..=TCP_PORT_MAX)
+            .map(|port| SocketAddr::new(ip, port))
+            .collect();
+        let Ok(socket) = TcpListener::bind(&addrs[..]) else {
+            return Err(NetworkError::CannotBind);
+        };
+        socket.set_nonblocking(true).unwrap();
+        Ok(Self::Tcp(socket))
+    }
+
+    /// Listen on the Unix domain socket at the given path.
+    ///
+    /// If the socket file is left from a previous run that crashed
+    /// (nobody accepts connections on it), the file is replaced.
+    #[cfg(unix)]
+    fn bind_unix(path: &std::path::Path) -> NetworkResult<Self> {
+        let socket = match UnixListener::bind(path) {
+            Ok(socket) => socket,
+            Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(NetworkError::CannotBind);
+                }
+                // Nobody listens on the socket, so it's a leftover from a crashed
+                // emulator. Never remove anything else that happens to be there.
+                let Ok(meta) = std::fs::symlink_metadata(path) else {
+                    return Err(NetworkError::CannotBind);
+                };
+                if !meta.file_type().is_socket() {
+                    return Err(NetworkError::Error("the socket path is not a socket"));
+                }
+                _ = std::fs::remove_file(path);
+                let Ok(socket) = UnixListener::bind(path) else {
+                    return Err(NetworkError::CannotBind);
+                };
+                socket
+            }
+            Err(_) => return Err(NetworkError::CannotBind),
+        };
+        socket.set_nonblocking(true).unwrap();
+        Ok(Self::Unix(socket, path.to_owned()))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_path: &std::path::Path) -> NetworkResult<Self> {
+        Err(NetworkError::Error(
+            "Unix domain sockets are not supported on this system",
+        ))
+    }
+
+    /// Accept a new connection, if any. Doesn't block.
+    fn accept(&self) -> std::io::Result<Stream> {
+        let stream = match self {
+            Self::Tcp(socket) => Stream::Tcp(socket.accept()?.0),
+            #[cfg(unix)]
+            Self::Unix(socket, _) => Stream::Unix(socket.accept()?.0),
+        };
+        stream.set_nonblocking()?;
+        Ok(stream)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // Unlike TCP ports, the socket file stays after the socket is closed.
+        #[cfg(unix)]
+        if let Self::Unix(_, path) = self {
+            _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A connection accepted by [Listener].
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn set_nonblocking(&self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nonblocking(true),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_nonblocking(true),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A connection to a serial client.
+struct Connection {
+    id: ClientId,
+    stream: Stream,
+    /// Reassembles the messages from the bytes received so far.
+    frames: framing::Deframer,
+    /// Encoded messages not yet written into the stream.
+    out: framing::FrameQueue,
+    /// True if the client has disconnected.
+    closed: bool,
+}
+
+impl Connection {
+    /// Write as many queued bytes as the stream accepts without blocking.
+    fn flush(&mut self) {
+        let stream = &mut self.stream;
+        self.out.flush(|bytes| stream.write(bytes).unwrap_or(0));
     }
 }
 
-/// A collection that holds 4 latest TCP connections.
+/// A collection that holds up to 4 serial connections.
 ///
-/// If there are already 4 TCP connections and a new one comes in,
+/// If there are already 4 connections and a new one comes in,
 /// the oldest one is dropped.
 struct RingBuf {
-    data: [Option<TcpStream>; 4],
-    next: usize,
+    data: [Option<Connection>; 4],
 }
 
 impl RingBuf {
     fn new() -> Self {
         Self {
             data: [None, None, None, None],
-            next: 0,
         }
     }
 
-    fn push(&mut self, val: TcpStream) {
-        self.data[self.next] = Some(val);
-        self.next = (self.next + 1) % 4
+    /// Add the connection. Returns the dropped connection, if there was no free slot.
+    fn push(&mut self, val: Connection) -> Option<Connection> {
+        if let Some(slot) = self.data.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(val);
+            return None;
+        }
+        // The IDs are assigned in order, so the oldest connection has the lowest ID.
+        let oldest = self
+            .data
+            .iter_mut()
+            .min_by_key(|slot| slot.as_ref().map(|conn| conn.id));
+        oldest.and_then(|slot| slot.replace(val))
+    }
+
+    /// Remove all closed connections, calling the callback for each.
+    fn remove_closed<F: FnMut(Connection)>(&mut self, mut f: F) {
+        for slot in &mut self.data {
+            if let Some(conn) = slot.take_if(|conn| conn.closed) {
+                f(conn);
+            }
+        }
     }
 
-    fn iter_mut(&mut self) -> impl Iterator<Item = &mut TcpStream> {
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Connection> {
         self.data.iter_mut().filter_map(Option::as_mut)
     }
 }
@@ -711,14 +2092,19 @@ fn start_audio(config: &DeviceConfig) -> Option<AudioWriter> {
     };
 
     let (send, recv) = mpsc::sync_channel(AUDIO_BUF_SIZE);
-    let Ok(mut stream) = rodio::OutputStreamBuilder::open_default_stream() else {
-        eprintln!("WARNING: audio device is not available, sound will be disabled");
-        return None;
-    };
-    stream.log_on_drop(false);
-    let mixer = stream.mixer();
     let source = AudioReader { wav, recv };
-    mixer.add(source);
+    let stream = match rodio::OutputStreamBuilder::open_default_stream() {
+        Ok(mut stream) => {
+            stream.log_on_drop(false);
+            stream.mixer().add(source);
+            Some(stream)
+        }
+        Err(_) => {
+            eprintln!("WARNING: audio device is not available, sound will be muted");
+            std::thread::spawn(move || run_null_sink(source));
+            None
+        }
+    };
     let audio = AudioWriter {
         buf: [0; AUDIO_BUF_SIZE],
         pending_from: 0,
@@ -729,13 +2115,45 @@ fn start_audio(config: &DeviceConfig) -> Option<AudioWriter> {
     Some(audio)
 }
 
+/// Consume audio samples at the real playback speed without playing them.
+///
+/// Used when there is no audio device available (on CI, servers, etc).
+/// It makes the audio buffer drain the same way as with a real audio device
+/// and so the WAV recording (if enabled) is still written.
+#[cfg(not(target_os = "android"))]
+fn run_null_sink(mut source: AudioReader) {
+    // Both channels are sent through the same queue.
+    const SAMPLES_PER_SECOND: u128 = SAMPLE_RATE as u128 * 2;
+    let start = std::time::Instant::now();
+    let mut consumed: u128 = 0;
+    loop {
+        let elapsed = start.elapsed().as_micros();
+        let expected = elapsed * SAMPLES_PER_SECOND / 1_000_000;
+        while consumed < expected {
+            match source.recv.try_recv() {
+                Ok(s) => source.write_wav(s),
+                // The runtime is behind, catch up on the next iteration.
+                Err(mpsc::TryRecvError::Empty) => break,
+                // The device is dropped, the WAV writer is finalized on drop.
+                Err(mpsc::TryRecvError::Disconnected) => return,
+            }
+            consumed += 1;
+        }
+        // If the runtime couldn't keep up, don't try to play the missed samples
+        // faster later, the same as a real audio device wouldn't.
+        consumed = expected;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
 struct AudioWriter {
     buf: [i16; AUDIO_BUF_SIZE],
     send: mpsc::SyncSender<i16>,
     pending_from: usize,
     pending_to: usize,
+    /// The audio output device. None if sound is played into the null sink.
     #[cfg(not(target_os = "android"))]
-    _stream: rodio::OutputStream,
+    _stream: Option<rodio::OutputStream>,
 }
 
 impl AudioWriter {
@@ -784,6 +2202,15 @@ struct AudioReader {
     recv: mpsc::Receiver<i16>,
 }
 
+#[cfg(not(target_os = "android"))]
+impl AudioReader {
+    fn write_wav(&mut self, s: i16) {
+        if let Some(wav) = self.wav.as_mut() {
+            wav.write_sample(s).unwrap()
+        }
+    }
+}
+
 #[cfg(not(target_os = "android"))]
 impl rodio::Source for AudioReader {
     fn current_span_len(&self) -> Option<usize> {
@@ -809,10 +2236,375 @@ impl Iterator for AudioReader {
 
     fn next(&mut self) -> Option<Self::Item> {
         let s = self.recv.recv().unwrap_or_default();
-        if let Some(wav) = self.wav.as_mut() {
-            wav.write_sample(s).unwrap()
-        }
+        self.write_wav(s);
         let s = f32::from(s) / f32::from(i16::MAX);
         Some(s)
     }
 }
+
+#[cfg(not(target_os = "android"))]
+fn start_audio_input(config: &DeviceConfig) -> Option<AudioInput> {
+    use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    let samples = CapturedSamples::default();
+    let out = samples.clone();
+    if let Some(path) = &config.wav_input {
+        let reader = match hound::WavReader::open(path) {
+            Ok(reader) => reader,
+            Err(err) => {
+                eprintln!("WARNING: cannot open audio input file: {err}");
+                return None;
+            }
+        };
+        std::thread::spawn(move || run_wav_input(reader, out));
+        let audio_in = AudioInput {
+            samples,
+            _stream: None,
+        };
+        return Some(audio_in);
+    }
+
+    let host = rodio::cpal::default_host();
+    let Some(device) = host.default_input_device() else {
+        eprintln!("WARNING: microphone is not available, audio input will be empty");
+        return None;
+    };
+    let Ok(supported) = device.default_input_config() else {
+        eprintln!("WARNING: microphone is not configured, audio input will be empty");
+        return None;
+    };
+    let format = supported.sample_format();
+    if !matches!(
+        format,
+        rodio::cpal::SampleFormat::F32 | rodio::cpal::SampleFormat::I16
+    ) {
+        eprintln!(
+            "WARNING: microphone sample format {format} is not supported, audio input will be empty"
+        );
+        return None;
+    }
+    let stream_config = supported.config();
+    let mut converter =
+        InputConverter::new(stream_config.channels, stream_config.sample_rate.0, out);
+    let on_data = move |data: &rodio::cpal::Data, _: &rodio::cpal::InputCallbackInfo| {
+        if let Some(samples) = data.as_slice::<f32>() {
+            converter.push(samples);
+        } else if let Some(samples) = data.as_slice::<i16>() {
+            let samples: Vec<f32> = samples.iter().map(|s| f32::from(*s) / 32768.).collect();
+            converter.push(&samples);
+        }
+    };
+    let on_error = |err| eprintln!("ERROR(audio): {err}");
+    let res = device.build_input_stream_raw(&stream_config, format, on_data, on_error, None);
+    let stream = match res {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("WARNING: cannot open microphone, audio input will be empty: {err}");
+            return None;
+        }
+    };
+    if let Err(err) = stream.play() {
+        eprintln!("WARNING: cannot start microphone, audio input will be empty: {err}");
+        return None;
+    }
+    let audio_in = AudioInput {
+        samples,
+        _stream: Some(stream),
+    };
+    Some(audio_in)
+}
+
+/// Feed the audio from the WAV file as if it was captured by microphone in real time.
+///
+/// When the file ends, the input stays silent.
+#[cfg(not(target_os = "android"))]
+fn run_wav_input<R: std::io::Read>(mut reader: hound::WavReader<R>, out: CapturedSamples) {
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().map_while(Result::ok).collect(),
+        hound::SampleFormat::Int => {
+            let scale = (1u32 << (spec.bits_per_sample - 1)) as f32;
+            let samples = reader.samples::<i32>().map_while(Result::ok);
+            samples.map(|s| s as f32 / scale).collect()
+        }
+    };
+    let channels = usize::from(spec.channels.max(1));
+    let mut converter = InputConverter::new(spec.channels, spec.sample_rate, out);
+    let start = std::time::Instant::now();
+    let mut sent = 0;
+    while sent < samples.len() {
+        let elapsed = start.elapsed().as_micros();
+        let frames = elapsed * u128::from(spec.sample_rate) / 1_000_000;
+        let expected = (frames as usize * channels).min(samples.len());
+        if !converter.push(&samples[sent..expected]) {
+            return;
+        }
+        sent = expected;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+/// Captured audio samples waiting to be read by the runtime.
+///
+/// Shared between the device and the thread capturing the audio.
+#[cfg(not(target_os = "android"))]
+#[derive(Clone, Default)]
+struct CapturedSamples(Arc<Mutex<VecDeque<i16>>>);
+
+/// The audio input started by [start_audio_input].
+#[cfg(not(target_os = "android"))]
+struct AudioInput {
+    samples: CapturedSamples,
+    /// The microphone stream. None if the input is read from a WAV file.
+    _stream: Option<rodio::cpal::Stream>,
+}
+
+#[cfg(not(target_os = "android"))]
+impl AudioInput {
+    /// Move all samples captured since the last read into the buffer.
+    fn read(&mut self, buf: &mut Vec<i16>) {
+        if let Ok(mut samples) = self.samples.0.lock() {
+            buf.extend(samples.drain(..));
+        }
+    }
+}
+
+/// Converts interleaved audio frames of any format into mono PCM at [SAMPLE_RATE].
+#[cfg(not(target_os = "android"))]
+struct InputConverter {
+    channels: usize,
+    sample_rate: u32,
+    /// The resampling accumulator. A sample is emitted each time it reaches the input rate.
+    acc: u32,
+    /// Where to put the converted samples.
+    out: CapturedSamples,
+}
+
+#[cfg(not(target_os = "android"))]
+impl InputConverter {
+    fn new(channels: u16, sample_rate: u32, out: CapturedSamples) -> Self {
+        Self {
+            channels: usize::from(channels.max(1)),
+            sample_rate: sample_rate.max(1),
+            acc: 0,
+            out,
+        }
+    }
+
+    /// Convert and store the samples. Returns false if the [AudioInput] is dropped.
+    fn push(&mut self, samples: &[f32]) -> bool {
+        if Arc::strong_count(&self.out.0) == 1 {
+            return false;
+        }
+        let Ok(mut buf) = self.out.0.lock() else {
+            return false;
+        };
+        for frame in samples.chunks(self.channels) {
+            let sum: f32 = frame.iter().sum();
+            let s = sum / frame.len() as f32;
+            let s = (s.clamp(-1., 1.) * f32::from(i16::MAX)) as i16;
+            self.acc += SAMPLE_RATE;
+            while self.acc >= self.sample_rate {
+                self.acc -= self.sample_rate;
+                // If the runtime doesn't read the input, the oldest samples are dropped.
+                if buf.len() >= AUDIO_IN_BUF_SIZE {
+                    buf.pop_front();
+                }
+                buf.push_back(s);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path in the temp directory unique for the test.
+    #[cfg(unix)]
+    fn temp_path(name: &str) -> PathBuf {
+        let name = format!("firefly-hal-{}-{name}", std::process::id());
+        std::env::temp_dir().join(name)
+    }
+
+    fn key(byte: u8, encrypt: bool) -> SessionKey {
+        SessionKey {
+            key: [byte; 32],
+            encrypt,
+        }
+    }
+
+    /// Seal the packet and return the fields of the resulting [Packet::Sealed].
+    fn seal(sealer: &Sealer, raw: &[u8]) -> (bool, [u8; 12], Vec<u8>) {
+        let sealed = sealer.seal(raw).unwrap();
+        let Ok(Packet::Sealed {
+            encrypted,
+            nonce,
+            data,
+        }) = postcard::from_bytes(&sealed)
+        else {
+            panic!("not a sealed packet");
+        };
+        (encrypted, nonce, data.to_vec())
+    }
+
+    #[test]
+    fn test_sealer_round_trip() {
+        for encrypt in [false, true] {
+            let sealer = Sealer::new(&key(1, encrypt));
+            let (encrypted, nonce, data) = seal(&sealer, b"hello");
+            assert_eq!(encrypted, encrypt);
+            // Only encrypted messages hide the content.
+            let visible = data.windows(5).any(|w| w == b"hello");
+            assert_eq!(visible, !encrypt);
+            let opened = sealer.open(encrypted, &nonce, &data);
+            assert_eq!(opened.as_deref(), Some(&b"hello"[..]));
+        }
+    }
+
+    #[test]
+    fn test_sealer_tampered() {
+        for encrypt in [false, true] {
+            let sealer = Sealer::new(&key(1, encrypt));
+            let (encrypted, nonce, data) = seal(&sealer, b"hello");
+            for i in 0..data.len() {
+                let mut tampered = data.clone();
+                tampered[i] ^= 1;
+                assert_eq!(sealer.open(encrypted, &nonce, &tampered), None);
+            }
+            let mut nonce = nonce;
+            nonce[0] ^= 1;
+            assert_eq!(sealer.open(encrypted, &nonce, &data), None);
+            assert_eq!(sealer.open(encrypted, &nonce, &data[..10]), None);
+        }
+    }
+
+    #[test]
+    fn test_sealer_wrong_key() {
+        let sealer = Sealer::new(&key(1, true));
+        let (encrypted, nonce, data) = seal(&sealer, b"hello");
+        let other = Sealer::new(&key(2, true));
+        assert_eq!(other.open(encrypted, &nonce, &data), None);
+
+        // The same key but the other mode.
+        let other = Sealer::new(&key(1, false));
+        assert_eq!(other.open(encrypted, &nonce, &data), None);
+        assert_eq!(other.open(false, &nonce, &data), None);
+    }
+
+    #[test]
+    fn test_seq_window_duplicates() {
+        let mut window = SeqWindow::new(10);
+        assert!(!window.insert(10));
+        assert!(window.insert(11));
+        assert!(!window.insert(11));
+        // Out of order but within the window.
+        assert!(window.insert(13));
+        assert!(window.insert(12));
+        assert!(!window.insert(12));
+        assert!(!window.insert(13));
+        assert!(window.insert(5));
+        assert!(!window.insert(5));
+    }
+
+    #[test]
+    fn test_seq_window_too_old() {
+        let mut window = SeqWindow::new(100);
+        assert!(window.insert(100 - 63));
+        assert!(!window.insert(100 - 64));
+        assert!(window.insert(200));
+        // The window moved, so all the old messages are dropped.
+        assert!(!window.insert(100));
+        assert!(!window.insert(136));
+        assert!(window.insert(137));
+    }
+
+    #[test]
+    fn test_seq_window_wrapping() {
+        let mut window = SeqWindow::new(u32::MAX - 1);
+        assert!(window.insert(u32::MAX));
+        assert!(window.insert(0));
+        assert!(window.insert(1));
+        assert!(!window.insert(u32::MAX));
+        assert!(window.insert(u32::MAX - 2));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_bind_unix_stale_socket() {
+        let path = temp_path("stale.sock");
+        _ = std::fs::remove_file(&path);
+        // The socket file stays after the listener is closed.
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = Listener::bind_unix(&path).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        // The socket is in use, so it must not be replaced.
+        let res = Listener::bind_unix(&path);
+        assert!(matches!(res, Err(NetworkError::CannotBind)));
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_bind_unix_regular_file() {
+        let path = temp_path("file.sock");
+        std::fs::write(&path, b"precious").unwrap();
+        assert!(Listener::bind_unix(&path).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"precious");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Read from the client socket until the given number of messages is received.
+    #[cfg(unix)]
+    fn read_messages(stream: &mut UnixStream, count: usize) -> Vec<Box<[u8]>> {
+        let mut deframer = framing::Deframer::new(framing::SERIAL_MAX_SIZE);
+        let mut messages = Vec::new();
+        let mut buf = [0; 64];
+        while messages.len() < count {
+            let size = stream.read(&mut buf).unwrap();
+            deframer.extend(&buf[..size], |msg| messages.push(msg.unwrap()));
+        }
+        messages
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_serial_routing() {
+        let path = temp_path("routing.sock");
+        let mut serial = SerialImpl::new();
+        let worker = serial.worker.replace(None).unwrap();
+        serial.thread = Some(worker.start(Listener::bind_unix(&path).unwrap()));
+
+        let timeout = std::time::Duration::from_secs(5);
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let client = UnixStream::connect(&path).unwrap();
+            client.set_read_timeout(Some(timeout)).unwrap();
+            let event = serial.r_in.recv_timeout(timeout).unwrap();
+            let Ok(SerialEvent::Connected(id)) = event else {
+                panic!("expected a new client");
+            };
+            clients.push((id, client));
+        }
+
+        let msg = framing::encode(b"second").into_boxed_slice();
+        serial.s_out.send((Some(clients[1].0), msg)).unwrap();
+        let msg = framing::encode(b"all").into_boxed_slice();
+        serial.s_out.send((None, msg)).unwrap();
+
+        // If the first client got the message addressed to the second one,
+        // it would be read before the broadcast.
+        let messages = read_messages(&mut clients[0].1, 1);
+        assert_eq!(&*messages[0], b"all");
+        let messages = read_messages(&mut clients[1].1, 2);
+        assert_eq!(&*messages[0], b"second");
+        assert_eq!(&*messages[1], b"all");
+
+        serial.stop().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -9,18 +9,30 @@ extern crate alloc;
 mod errors;
 mod shared;
 
+pub mod framing;
+pub mod reliable;
+
+#[cfg(any(test, target_os = "none"))]
+mod fat;
+
+#[cfg(all(not(target_os = "none"), any(test, feature = "mock")))]
+pub mod mock;
+
 #[cfg_attr(target_family = "wasm", path = "web.rs")]
 #[cfg_attr(not(target_os = "none"), path = "hosted.rs")]
 #[cfg_attr(target_os = "none", path = "embedded.rs")]
 mod device;
 
+#[cfg(not(any(target_os = "none", target_family = "wasm")))]
+mod replay;
+
 #[cfg(not(target_os = "none"))]
 #[cfg_attr(target_os = "android", path = "gamepad_android.rs")]
 #[cfg_attr(not(target_os = "android"), path = "gamepad.rs")]
 mod gamepad;
 
 #[cfg(not(target_os = "none"))]
-pub use device::DeviceConfig;
+pub use device::{DeviceConfig, Discovery, NetConditions};
 
 pub use device::{Addr, DeviceImpl, DirImpl};
 pub use errors::*;
@@ -0,0 +1,1020 @@
+//! In-memory [Device] implementation for deterministic tests.
+//!
+//! Nothing here touches real hardware: the clock, input, random numbers,
+//! network peers, serial port, and audio sink are all scripted by the test
+//! through the methods on [MockDevice]. The filesystem lives in memory
+//! and is shared between the device and all [MockDir] handles.
+use crate::*;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::fmt::Display;
+use core::net::IpAddr;
+use firefly_types::spi::SendStatus;
+
+/// Network address of a mock peer.
+pub type MockAddr = u32;
+
+/// How many audio samples [Device::get_audio_buffer] returns by default.
+const AUDIO_BUF_SIZE: usize = SAMPLE_RATE as usize / 12;
+
+/// The default seed for random numbers when no values are scripted.
+const DEFAULT_SEED: u32 = 0x2545_f491;
+
+pub struct MockDevice {
+    /// The current time. Advanced only by [Device::delay] and [MockDevice::advance].
+    clock: Rc<MockClock>,
+    /// Scripted input states. The last one is repeated when the queue is exhausted.
+    inputs: VecDeque<InputState>,
+    last_input: Option<InputState>,
+    /// Scripted random numbers. When exhausted, falls back to xorshift.
+    randoms: VecDeque<u32>,
+    seed: u32,
+    name: Option<&'static str>,
+    logs: Vec<String>,
+    fs: Rc<RefCell<MockFs>>,
+    headphones: bool,
+    battery: Option<BatteryStatus>,
+    audio_buf: Vec<i16>,
+    audio_size: usize,
+    /// How many samples of `audio_buf` were given out on the last call.
+    audio_pending: usize,
+    audio_out: Vec<i16>,
+    /// Samples to be returned by the next call to [Device::read_audio].
+    audio_in: Vec<i16>,
+    /// Samples returned by the previous call to [Device::read_audio].
+    audio_in_read: Vec<i16>,
+    net_started: bool,
+    local_addr: MockAddr,
+    max_message_size: usize,
+    advertisements: usize,
+    advertisement: Advertisement,
+    peers: BTreeMap<MockAddr, Peer<MockAddr>>,
+    session_key: Option<SessionKey>,
+    net_in: VecDeque<(MockAddr, Box<[u8]>)>,
+    net_out: Vec<(MockAddr, Box<[u8]>)>,
+    /// Messages sent by [Network::net_send_reliable].
+    net_out_reliable: Vec<(MockAddr, Box<[u8]>)>,
+    send_statuses: BTreeMap<MockAddr, SendStatus>,
+    serial_started: bool,
+    serial_in: VecDeque<SerialEvent>,
+    serial_out: Vec<(Option<ClientId>, Box<[u8]>)>,
+    wifi_status: u8,
+    tcp_connected: bool,
+    tcp_in: VecDeque<Box<[u8]>>,
+    tcp_out: Vec<Box<[u8]>>,
+}
+
+impl MockDevice {
+    pub fn new() -> Self {
+        let clock = Rc::new(MockClock {
+            us: Cell::new(0),
+            utc_base: Cell::new(None),
+        });
+        Self {
+            clock: Rc::clone(&clock),
+            inputs: VecDeque::new(),
+            last_input: None,
+            randoms: VecDeque::new(),
+            seed: DEFAULT_SEED,
+            name: None,
+            logs: Vec::new(),
+            fs: Rc::new(RefCell::new(MockFs::new(clock))),
+            headphones: false,
+            battery: None,
+            audio_buf: Vec::new(),
+            audio_size: AUDIO_BUF_SIZE,
+            audio_pending: 0,
+            audio_out: Vec::new(),
+            audio_in: Vec::new(),
+            audio_in_read: Vec::new(),
+            net_started: false,
+            local_addr: 0,
+            max_message_size: MAX_MESSAGE_SIZE,
+            advertisements: 0,
+            advertisement: Advertisement::default(),
+            peers: BTreeMap::new(),
+            session_key: None,
+            net_in: VecDeque::new(),
+            net_out: Vec::new(),
+            net_out_reliable: Vec::new(),
+            send_statuses: BTreeMap::new(),
+            serial_started: false,
+            serial_in: VecDeque::new(),
+            serial_out: Vec::new(),
+            wifi_status: 2,
+            tcp_connected: false,
+            tcp_in: VecDeque::new(),
+            tcp_out: Vec::new(),
+        }
+    }
+
+    /// Move the clock forward without calling [Device::delay].
+    pub fn advance(&self, d: Duration) {
+        let us = &self.clock.us;
+        us.set(us.get().saturating_add(d.us));
+    }
+
+    /// Queue an input state to be returned by [Device::read_input].
+    ///
+    /// When the queue is empty, the last returned state is repeated.
+    pub fn push_input(&mut self, input: InputState) {
+        self.inputs.push_back(input);
+    }
+
+    /// Queue a value to be returned by [Device::random].
+    pub fn push_random(&mut self, val: u32) {
+        self.randoms.push_back(val);
+    }
+
+    /// Set the seed used for random numbers when none are queued.
+    ///
+    /// Zero is not a valid xorshift state, so it is replaced by the default seed.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = if seed == 0 { DEFAULT_SEED } else { seed };
+    }
+
+    pub fn set_name(&mut self, name: Option<&'static str>) {
+        self.name = name;
+    }
+
+    /// All messages logged so far, both debug and errors.
+    pub fn logs(&self) -> &[String] {
+        &self.logs
+    }
+
+    pub fn set_headphones(&mut self, connected: bool) {
+        self.headphones = connected;
+    }
+
+    pub fn set_battery_status(&mut self, status: Option<BatteryStatus>) {
+        self.battery = status;
+    }
+
+    /// Create a file with the given content, including all parent directories.
+    ///
+    /// The path is a slice of path components, the same as for [Device::open_dir]
+    /// but including the file name.
+    pub fn write_file(&mut self, path: &[&str], data: &[u8]) {
+        let path = path.join("/");
+        let mut fs = self.fs.borrow_mut();
+        fs.create_parents(&path);
+        fs.touch(&path);
+        fs.files.insert(path, data.to_vec());
+    }
+
+    /// Get the content of the file at the given path, if it exists.
+    pub fn read_file(&self, path: &[&str]) -> Option<Vec<u8>> {
+        let path = path.join("/");
+        self.fs.borrow().files.get(&path).cloned()
+    }
+
+    /// Create the directory at the given path, including all parent directories.
+    pub fn create_dir_all(&mut self, path: &[&str]) {
+        let path = path.join("/");
+        let mut fs = self.fs.borrow_mut();
+        fs.create_parents(&path);
+        fs.dirs.insert(path);
+    }
+
+    /// Set how many samples [Device::get_audio_buffer] returns on each call.
+    pub fn set_audio_size(&mut self, size: usize) {
+        self.audio_size = size;
+    }
+
+    /// Take all audio samples written by the runtime so far.
+    pub fn take_audio(&mut self) -> Vec<i16> {
+        self.flush_audio();
+        core::mem::take(&mut self.audio_out)
+    }
+
+    /// Add samples to be returned by the next call to [Device::read_audio].
+    pub fn push_audio_input(&mut self, samples: &[i16]) {
+        self.audio_in.extend_from_slice(samples);
+    }
+
+    fn flush_audio(&mut self) {
+        let pending = &self.audio_buf[..self.audio_pending];
+        self.audio_out.extend_from_slice(pending);
+        self.audio_pending = 0;
+    }
+
+    pub fn set_local_addr(&mut self, addr: MockAddr) {
+        self.local_addr = addr;
+    }
+
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// How many times [Network::net_advertise] was called.
+    pub fn advertisements(&self) -> usize {
+        self.advertisements
+    }
+
+    /// The data set by [Network::net_set_advertisement].
+    pub fn advertisement(&self) -> &Advertisement {
+        &self.advertisement
+    }
+
+    /// Receive an advertisement from the given peer.
+    ///
+    /// The peer is listed by [Network::net_peers] as seen at the current mock time.
+    pub fn push_peer(&mut self, addr: MockAddr, advertisement: Advertisement) {
+        let last_seen = self.now();
+        let peer = Peer {
+            addr,
+            advertisement,
+            last_seen,
+        };
+        self.peers.insert(addr, peer);
+    }
+
+    /// The key set by [Network::net_set_key].
+    pub fn session_key(&self) -> Option<&SessionKey> {
+        self.session_key.as_ref()
+    }
+
+    /// Deliver a message from the given peer to be returned by [Network::net_recv].
+    pub fn push_net_message(&mut self, addr: MockAddr, data: &[u8]) {
+        self.net_in.push_back((addr, data.into()));
+    }
+
+    /// Take all messages sent by the runtime so far using [Network::net_send].
+    pub fn take_net_messages(&mut self) -> Vec<(MockAddr, Box<[u8]>)> {
+        core::mem::take(&mut self.net_out)
+    }
+
+    /// Take all messages sent by the runtime so far using [Network::net_send_reliable].
+    pub fn take_reliable_net_messages(&mut self) -> Vec<(MockAddr, Box<[u8]>)> {
+        core::mem::take(&mut self.net_out_reliable)
+    }
+
+    /// Set the status to be returned by [Network::net_send_status] for the peer.
+    pub fn set_send_status(&mut self, addr: MockAddr, status: SendStatus) {
+        self.send_statuses.insert(addr, status);
+    }
+
+    /// Deliver a message to be returned by [Serial::serial_recv].
+    ///
+    /// The message comes from the client 0.
+    pub fn push_serial_message(&mut self, data: &[u8]) {
+        self.push_serial_event(SerialEvent::Message(0, data.into()));
+    }
+
+    /// Deliver an event to be returned by [Serial::serial_recv_event].
+    pub fn push_serial_event(&mut self, event: SerialEvent) {
+        self.serial_in.push_back(event);
+    }
+
+    /// Take all messages sent into the serial port so far.
+    pub fn take_serial_messages(&mut self) -> Vec<Box<[u8]>> {
+        let messages = self.take_serial_messages_to();
+        messages.into_iter().map(|(_, data)| data).collect()
+    }
+
+    /// Like [MockDevice::take_serial_messages] but also returns the recipient of each message.
+    ///
+    /// None means the message was sent to all clients.
+    pub fn take_serial_messages_to(&mut self) -> Vec<(Option<ClientId>, Box<[u8]>)> {
+        core::mem::take(&mut self.serial_out)
+    }
+
+    /// Deliver a chunk to be returned by [Wifi::tcp_recv].
+    pub fn push_tcp_chunk(&mut self, data: &[u8]) {
+        self.tcp_in.push_back(data.into());
+    }
+
+    /// Take all chunks sent into the TCP connection so far.
+    pub fn take_tcp_chunks(&mut self) -> Vec<Box<[u8]>> {
+        core::mem::take(&mut self.tcp_out)
+    }
+}
+
+impl Device for MockDevice {
+    type Dir = MockDir;
+
+    fn now(&self) -> Instant {
+        Instant {
+            us: self.clock.us.get(),
+        }
+    }
+
+    fn delay(&self, d: Duration) {
+        self.advance(d);
+    }
+
+    fn now_utc(&self) -> Option<DateTime> {
+        self.clock.now_utc()
+    }
+
+    fn set_utc(&mut self, now: DateTime) {
+        let uptime = self.clock.us.get() / 1_000_000;
+        let Some(now) = now.to_unix() else {
+            return;
+        };
+        let base = now.saturating_sub(uptime);
+        self.clock.utc_base.set(Some(base));
+    }
+
+    fn read_input(&mut self) -> Option<InputState> {
+        if let Some(input) = self.inputs.pop_front() {
+            self.last_input = Some(input);
+        }
+        self.last_input.clone()
+    }
+
+    fn get_name(&mut self) -> Option<&'static str> {
+        self.name
+    }
+
+    fn log_debug<D: Display>(&mut self, src: &str, msg: D) {
+        self.logs.push(alloc::format!("DEBUG({src}): {msg}"));
+    }
+
+    fn log_error<D: Display>(&mut self, src: &str, msg: D) {
+        self.logs.push(alloc::format!("ERROR({src}): {msg}"));
+    }
+
+    fn random(&mut self) -> u32 {
+        if let Some(val) = self.randoms.pop_front() {
+            return val;
+        }
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed = x;
+        x
+    }
+
+    fn open_dir(&mut self, path: &[&str]) -> Result<Self::Dir, FSError> {
+        let path = path.join("/");
+        let fs = self.fs.borrow();
+        if fs.files.contains_key(&path) {
+            return Err(FSError::OpenedFileAsDir);
+        }
+        if !fs.dirs.contains(&path) {
+            return Err(FSError::NotFound);
+        }
+        Ok(MockDir {
+            fs: Rc::clone(&self.fs),
+            path,
+        })
+    }
+
+    fn has_headphones(&mut self) -> bool {
+        self.headphones
+    }
+
+    fn get_audio_buffer(&mut self) -> &mut [i16] {
+        self.flush_audio();
+        self.audio_buf.clear();
+        self.audio_buf.resize(self.audio_size, 0);
+        self.audio_pending = self.audio_size;
+        &mut self.audio_buf
+    }
+
+    fn read_audio(&mut self) -> &[i16] {
+        self.audio_in_read.clear();
+        core::mem::swap(&mut self.audio_in, &mut self.audio_in_read);
+        &self.audio_in_read
+    }
+
+    fn get_battery_status(&mut self) -> Option<BatteryStatus> {
+        self.battery
+    }
+}
+
+impl Network for MockDevice {
+    type Addr = MockAddr;
+
+    fn net_start(&mut self) -> NetworkResult<()> {
+        self.net_started = true;
+        Ok(())
+    }
+
+    fn net_stop(&mut self) -> NetworkResult<()> {
+        self.net_started = false;
+        Ok(())
+    }
+
+    fn net_local_addr(&self) -> MockAddr {
+        self.local_addr
+    }
+
+    fn net_advertise(&mut self) -> NetworkResult<()> {
+        if !self.net_started {
+            return Err(NetworkError::NotInitialized);
+        }
+        self.advertisements += 1;
+        Ok(())
+    }
+
+    fn net_set_advertisement(&mut self, ad: Advertisement) -> NetworkResult<()> {
+        let raw = postcard::to_allocvec(&ad)?;
+        if raw.len() > self.max_message_size {
+            return Err(NetworkError::OutMessageTooBig);
+        }
+        self.advertisement = ad;
+        Ok(())
+    }
+
+    fn net_peers(&mut self) -> NetworkResult<Vec<Peer<MockAddr>>> {
+        if !self.net_started {
+            return Err(NetworkError::NotInitialized);
+        }
+        Ok(self.peers.values().cloned().collect())
+    }
+
+    fn net_set_key(&mut self, key: Option<SessionKey>) -> NetworkResult<()> {
+        self.session_key = key;
+        Ok(())
+    }
+
+    fn net_recv(&mut self) -> NetworkResult<Option<(MockAddr, Box<[u8]>)>> {
+        if !self.net_started {
+            return Err(NetworkError::NotInitialized);
+        }
+        Ok(self.net_in.pop_front())
+    }
+
+    fn net_send(&mut self, addr: MockAddr, data: &[u8]) -> NetworkResult<()> {
+        if !self.net_started {
+            return Err(NetworkError::NotInitialized);
+        }
+        if data.len() > self.max_message_size {
+            return Err(NetworkError::OutMessageTooBig);
+        }
+        self.net_out.push((addr, data.into()));
+        Ok(())
+    }
+
+    /// There is no packet loss in the mock, so the message is just recorded.
+    /// Tests can check that the runtime used the reliable mode
+    /// with [MockDevice::take_reliable_net_messages].
+    fn net_send_reliable(&mut self, addr: MockAddr, data: &[u8]) -> NetworkResult<()> {
+        if !self.net_started {
+            return Err(NetworkError::NotInitialized);
+        }
+        if data.len() > self.max_message_size {
+            return Err(NetworkError::OutMessageTooBig);
+        }
+        self.net_out_reliable.push((addr, data.into()));
+        Ok(())
+    }
+
+    fn net_max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    fn net_send_status(&mut self, addr: MockAddr) -> NetworkResult<SendStatus> {
+        let status = self.send_statuses.get(&addr).copied();
+        Ok(status.unwrap_or(SendStatus::Empty))
+    }
+}
+
+impl Serial for MockDevice {
+    fn serial_start(&mut self) -> NetworkResult<()> {
+        self.serial_started = true;
+        Ok(())
+    }
+
+    fn serial_stop(&mut self) -> NetworkResult<()> {
+        self.serial_started = false;
+        Ok(())
+    }
+
+    fn serial_recv_event(&mut self) -> NetworkResult<Option<SerialEvent>> {
+        if !self.serial_started {
+            return Ok(None);
+        }
+        Ok(self.serial_in.pop_front())
+    }
+
+    fn serial_send_to(&mut self, client: Option<ClientId>, data: &[u8]) -> NetworkResult<()> {
+        if data.len() > framing::SERIAL_MAX_SIZE {
+            return Err(NetworkError::OutMessageTooBig);
+        }
+        if self.serial_started {
+            self.serial_out.push((client, data.into()));
+        }
+        Ok(())
+    }
+
+    fn serial_queued(&self) -> usize {
+        0
+    }
+
+    fn serial_dropped(&self) -> usize {
+        0
+    }
+}
+
+impl Wifi for MockDevice {
+    fn wifi_scan(&mut self) -> NetworkResult<[String; 6]> {
+        let points = ["Default Network", "", "", "", "", ""];
+        let points = points.map(|s| s.to_string());
+        Ok(points)
+    }
+
+    fn wifi_connect(&mut self, ssid: &str, pass: &str) -> NetworkResult<()> {
+        if ssid != "Default Network" {
+            self.wifi_status = 2; // disconnected
+        } else if pass == "invalid" {
+            self.wifi_status = 1; // error
+        } else {
+            self.wifi_status = 4; // connected
+        }
+        Ok(())
+    }
+
+    fn wifi_status(&mut self) -> NetworkResult<u8> {
+        Ok(self.wifi_status)
+    }
+
+    fn wifi_disconnect(&mut self) -> NetworkResult<()> {
+        self.wifi_status = 2;
+        self.tcp_connected = false;
+        Ok(())
+    }
+
+    fn tcp_connect(&mut self, _ip: IpAddr, _port: u16) -> NetworkResult<()> {
+        if self.wifi_status != 4 {
+            return Err(NetworkError::Error("not connected to wifi"));
+        }
+        self.tcp_connected = true;
+        Ok(())
+    }
+
+    fn tcp_status(&mut self) -> NetworkResult<u8> {
+        Ok(if self.tcp_connected { 5 } else { 1 })
+    }
+
+    fn tcp_send(&mut self, data: &[u8]) -> NetworkResult<()> {
+        if !self.tcp_connected {
+            return Err(NetworkError::NotInitialized);
+        }
+        self.tcp_out.push(data.into());
+        Ok(())
+    }
+
+    fn tcp_recv(&mut self) -> NetworkResult<Box<[u8]>> {
+        if !self.tcp_connected {
+            return Err(NetworkError::NotInitialized);
+        }
+        let chunk = self.tcp_in.pop_front().unwrap_or_default();
+        Ok(chunk)
+    }
+
+    fn tcp_close(&mut self) -> NetworkResult<()> {
+        self.tcp_connected = false;
+        Ok(())
+    }
+}
+
+/// The clock shared by the device and the filesystem.
+struct MockClock {
+    /// Microseconds since the device was created.
+    us: Cell<u64>,
+    /// Seconds since the Unix epoch at the moment when the clock was zero.
+    utc_base: Cell<Option<u64>>,
+}
+
+impl MockClock {
+    fn now_utc(&self) -> Option<DateTime> {
+        let base = self.utc_base.get()?;
+        let now = base.saturating_add(self.us.get() / 1_000_000);
+        Some(DateTime::from_unix(now))
+    }
+}
+
+/// The in-memory filesystem.
+///
+/// Paths are path components joined with "/". The root directory is "".
+struct MockFs {
+    clock: Rc<MockClock>,
+    dirs: BTreeSet<String>,
+    files: BTreeMap<String, Vec<u8>>,
+    /// The last modification time of files.
+    ///
+    /// Files modified before the UTC time was set are not listed.
+    modified: BTreeMap<String, DateTime>,
+}
+
+impl MockFs {
+    fn new(clock: Rc<MockClock>) -> Self {
+        let mut dirs = BTreeSet::new();
+        dirs.insert(String::new());
+        Self {
+            clock,
+            dirs,
+            files: BTreeMap::new(),
+            modified: BTreeMap::new(),
+        }
+    }
+
+    /// Update the modification time of the file.
+    fn touch(&mut self, path: &str) {
+        match self.clock.now_utc() {
+            Some(now) => self.modified.insert(path.to_string(), now),
+            None => self.modified.remove(path),
+        };
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, FSError> {
+        if self.dirs.contains(path) {
+            return Ok(Metadata {
+                kind: EntryKind::Dir,
+                size: 0,
+                modified: None,
+            });
+        }
+        let Some(data) = self.files.get(path) else {
+            return Err(FSError::NotFound);
+        };
+        Ok(Metadata {
+            kind: EntryKind::File,
+            size: data.len() as u32,
+            modified: self.modified.get(path).copied(),
+        })
+    }
+
+    /// Create all directories containing the given path.
+    fn create_parents(&mut self, path: &str) {
+        let mut parent = path;
+        while let Some((head, _)) = parent.rsplit_once('/') {
+            self.dirs.insert(head.to_string());
+            parent = head;
+        }
+    }
+}
+
+/// Join a directory path and a name inside of it.
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        alloc::format!("{dir}/{name}")
+    }
+}
+
+/// Check if the path is the given directory itself or is nested in it.
+fn is_within(path: &str, dir: &str) -> bool {
+    if dir.is_empty() {
+        return true;
+    }
+    match path.strip_prefix(dir) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+pub struct MockDir {
+    fs: Rc<RefCell<MockFs>>,
+    path: String,
+}
+
+impl MockDir {
+    fn open(&self, name: &str) -> MockFile {
+        MockFile {
+            fs: Rc::clone(&self.fs),
+            path: join_path(&self.path, name),
+            pos: 0,
+        }
+    }
+}
+
+impl Dir for MockDir {
+    type Read = MockFile;
+    type Write = MockFile;
+    type ReadWrite = MockFile;
+
+    fn open_file(&mut self, name: &str) -> Result<Self::Read, FSError> {
+        let file = self.open(name);
+        let fs = self.fs.borrow();
+        if fs.dirs.contains(&file.path) {
+            return Err(FSError::OpenedDirAsFile);
+        }
+        if !fs.files.contains_key(&file.path) {
+            return Err(FSError::NotFound);
+        }
+        Ok(file)
+    }
+
+    fn create_file(&mut self, name: &str) -> Result<Self::Write, FSError> {
+        let file = self.open(name);
+        let mut fs = self.fs.borrow_mut();
+        if fs.dirs.contains(&file.path) {
+            return Err(FSError::OpenedDirAsFile);
+        }
+        fs.create_parents(&file.path);
+        fs.touch(&file.path);
+        fs.files.insert(file.path.clone(), Vec::new());
+        Ok(file)
+    }
+
+    fn append_file(&mut self, name: &str) -> Result<Self::Write, FSError> {
+        let mut file = self.open(name);
+        let fs = self.fs.borrow();
+        let Some(data) = fs.files.get(&file.path) else {
+            return Err(FSError::NotFound);
+        };
+        file.pos = data.len();
+        Ok(file)
+    }
+
+    fn open_file_rw(&mut self, name: &str) -> Result<Self::ReadWrite, FSError> {
+        self.open_file(name)
+    }
+
+    fn get_file_size(&mut self, name: &str) -> Result<u32, FSError> {
+        let path = join_path(&self.path, name);
+        let fs = self.fs.borrow();
+        let Some(data) = fs.files.get(&path) else {
+            return Err(FSError::NotFound);
+        };
+        Ok(data.len() as u32)
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Metadata, FSError> {
+        let path = join_path(&self.path, name);
+        self.fs.borrow().metadata(&path)
+    }
+
+    fn remove_file(&mut self, name: &str) -> Result<(), FSError> {
+        let path = join_path(&self.path, name);
+        let mut fs = self.fs.borrow_mut();
+        if fs.dirs.contains(&path) {
+            return Err(FSError::DeleteDirAsFile);
+        }
+        fs.files.remove(&path);
+        fs.modified.remove(&path);
+        Ok(())
+    }
+
+    fn rename_file(&mut self, from: &str, to: &str) -> Result<(), FSError> {
+        let from = join_path(&self.path, from);
+        let to = join_path(&self.path, to);
+        let mut fs = self.fs.borrow_mut();
+        if fs.dirs.contains(&to) {
+            return Err(FSError::OpenedDirAsFile);
+        }
+        let Some(data) = fs.files.remove(&from) else {
+            return Err(FSError::NotFound);
+        };
+        fs.files.insert(to.clone(), data);
+        match fs.modified.remove(&from) {
+            Some(modified) => fs.modified.insert(to, modified),
+            None => fs.modified.remove(&to),
+        };
+        Ok(())
+    }
+
+    fn create_dir(&mut self, name: &str) -> Result<(), FSError> {
+        let path = join_path(&self.path, name);
+        let mut fs = self.fs.borrow_mut();
+        if fs.dirs.contains(&path) {
+            return Err(FSError::DirAlreadyExists);
+        }
+        if fs.files.contains_key(&path) {
+            return Err(FSError::FileAlreadyExists);
+        }
+        fs.dirs.insert(path);
+        Ok(())
+    }
+
+    fn remove_dir(self) -> Result<(), FSError> {
+        let mut fs = self.fs.borrow_mut();
+        fs.files.retain(|path, _| !is_within(path, &self.path));
+        fs.modified.retain(|path, _| !is_within(path, &self.path));
+        fs.dirs.retain(|path| !is_within(path, &self.path));
+        // The root directory cannot be removed, only cleared.
+        fs.dirs.insert(String::new());
+        Ok(())
+    }
+
+    fn iter_dir<F>(&mut self, mut f: F) -> Result<(), FSError>
+    where
+        F: FnMut(EntryKind, &[u8]),
+    {
+        self.iter_dir_meta(|meta, name| f(meta.kind, name))
+    }
+
+    fn iter_dir_meta<F>(&mut self, mut f: F) -> Result<(), FSError>
+    where
+        F: FnMut(&Metadata, &[u8]),
+    {
+        let fs = self.fs.borrow();
+        if !fs.dirs.contains(&self.path) {
+            return Err(FSError::NotFound);
+        }
+        let prefix = join_path(&self.path, "");
+        let children = fs.dirs.iter().chain(fs.files.keys());
+        for path in children {
+            if let Some(name) = path.strip_prefix(&prefix)
+                && !name.is_empty()
+                && !name.contains('/')
+            {
+                let meta = fs.metadata(path)?;
+                f(&meta, name.as_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A handle for a file in the in-memory filesystem.
+///
+/// All reads and writes go directly into the shared filesystem,
+/// so there is nothing to flush.
+pub struct MockFile {
+    fs: Rc<RefCell<MockFs>>,
+    path: String,
+    pos: usize,
+}
+
+impl embedded_io::ErrorType for MockFile {
+    type Error = embedded_io::ErrorKind;
+}
+
+impl embedded_io::Read for MockFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let fs = self.fs.borrow();
+        let Some(data) = fs.files.get(&self.path) else {
+            return Err(embedded_io::ErrorKind::NotFound);
+        };
+        let data = data.get(self.pos..).unwrap_or_default();
+        let size = data.len().min(buf.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        self.pos += size;
+        Ok(size)
+    }
+}
+
+impl embedded_io::Write for MockFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let mut fs = self.fs.borrow_mut();
+        let Some(data) = fs.files.get_mut(&self.path) else {
+            return Err(embedded_io::ErrorKind::NotFound);
+        };
+        let end = self.pos + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[self.pos..end].copy_from_slice(buf);
+        self.pos = end;
+        fs.touch(&self.path);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl embedded_io::Seek for MockFile {
+    fn seek(&mut self, pos: embedded_io::SeekFrom) -> Result<u64, Self::Error> {
+        let fs = self.fs.borrow();
+        let Some(data) = fs.files.get(&self.path) else {
+            return Err(embedded_io::ErrorKind::NotFound);
+        };
+        use embedded_io::SeekFrom::*;
+        let pos = match pos {
+            Start(n) => Some(n),
+            End(n) => (data.len() as u64).checked_add_signed(n),
+            Current(n) => (self.pos as u64).checked_add_signed(n),
+        };
+        let Some(pos) = pos else {
+            return Err(embedded_io::ErrorKind::InvalidInput);
+        };
+        self.pos = pos as usize;
+        Ok(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_io::{Read, Write};
+
+    #[test]
+    fn test_scripted_input_and_random() {
+        let mut device = MockDevice::new();
+        assert!(device.read_input().is_none());
+        let input = InputState {
+            buttons: 0b101,
+            ..Default::default()
+        };
+        device.push_input(input);
+        assert_eq!(device.read_input().unwrap().buttons, 0b101);
+        // The last input is repeated when the queue is empty.
+        assert_eq!(device.read_input().unwrap().buttons, 0b101);
+
+        device.push_random(42);
+        assert_eq!(device.random(), 42);
+        let mut other = MockDevice::new();
+        other.push_random(0);
+        other.random();
+        assert_eq!(device.random(), other.random());
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut device = MockDevice::new();
+        assert_eq!(device.now(), Instant { us: 0 });
+        device.delay(Duration::from_ms(16));
+        device.advance(Duration::from_s(1));
+        assert_eq!(device.now(), Instant { us: 1_016_000 });
+
+        assert_eq!(device.now_utc(), None);
+        let mut invalid = DateTime::from_unix(0);
+        invalid.day = 0;
+        device.set_utc(invalid);
+        assert_eq!(device.now_utc(), None);
+        let utc = DateTime::from_unix(1_700_000_000);
+        device.set_utc(utc);
+        device.advance(Duration::from_s(2));
+        let expected = DateTime::from_unix(1_700_000_002);
+        assert_eq!(device.now_utc(), Some(expected));
+    }
+
+    #[test]
+    fn test_filesystem() {
+        let mut device = MockDevice::new();
+        device.write_file(&["data", "author", "app", "save"], b"old");
+        let mut dir = device.open_dir(&["data", "author", "app"]).unwrap();
+        dir.replace_file("save", b"new content").unwrap();
+        assert_eq!(dir.get_file_size("save").unwrap(), 11);
+
+        let mut file = dir.open_file_rw("save").unwrap();
+        file.write_all(b"NEW").unwrap();
+        let mut buf = [0; 8];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b" content");
+        let content = device.read_file(&["data", "author", "app", "save"]);
+        assert_eq!(content.as_deref(), Some(&b"NEW content"[..]));
+
+        let mut names = Vec::new();
+        dir.iter_dir(|kind, name| names.push((kind, name.to_vec())))
+            .unwrap();
+        assert_eq!(names, [(EntryKind::File, b"save".to_vec())]);
+
+        // A failed replace doesn't leave the temporary file behind.
+        let res = dir.replace_file("_REPLACE.TMP", b"data");
+        assert!(matches!(res, Err(FSError::InvalidInput)));
+        dir.create_dir("sub").unwrap();
+        assert!(dir.replace_file("sub", b"data").is_err());
+        assert!(matches!(
+            dir.get_file_size("_REPLACE.TMP"),
+            Err(FSError::NotFound)
+        ));
+
+        let dir = device.open_dir(&["data", "author"]).unwrap();
+        dir.remove_dir().unwrap();
+        let res = device.open_dir(&["data", "author", "app"]);
+        assert!(matches!(res, Err(FSError::NotFound)));
+        assert!(device.open_dir(&["data"]).is_ok());
+    }
+
+    #[test]
+    fn test_network() {
+        let mut device = MockDevice::new();
+        let res = device.net_send(1, b"hi");
+        assert!(matches!(res, Err(NetworkError::NotInitialized)));
+        device.net_start().unwrap();
+        device.net_send(1, b"hi").unwrap();
+        assert_eq!(device.take_net_messages(), [(1, b"hi"[..].into())]);
+        assert!(device.take_net_messages().is_empty());
+        device.net_send_reliable(1, b"ok").unwrap();
+        assert!(device.take_net_messages().is_empty());
+        assert_eq!(device.take_reliable_net_messages(), [(1, b"ok"[..].into())]);
+
+        let big = alloc::vec![0; MAX_MESSAGE_SIZE + 1];
+        let res = device.net_send(1, &big);
+        assert!(matches!(res, Err(NetworkError::OutMessageTooBig)));
+
+        device.push_net_message(2, b"hello");
+        let msg = device.net_recv().unwrap();
+        assert_eq!(msg, Some((2, b"hello"[..].into())));
+        assert_eq!(device.net_recv().unwrap(), None);
+    }
+
+    #[test]
+    fn test_serial() {
+        let mut device = MockDevice::new();
+        device.push_serial_event(SerialEvent::Connected(3));
+        device.push_serial_message(b"ping");
+        assert_eq!(device.serial_recv().unwrap(), None);
+        device.serial_start().unwrap();
+        assert_eq!(device.serial_recv().unwrap(), Some(b"ping"[..].into()));
+        device.serial_send_to(Some(3), b"pong").unwrap();
+        device.serial_send(b"all").unwrap();
+        let sent = device.take_serial_messages_to();
+        assert_eq!(sent[0], (Some(3), b"pong"[..].into()));
+        assert_eq!(sent[1], (None, b"all"[..].into()));
+    }
+}
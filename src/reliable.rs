@@ -0,0 +1,466 @@
+//! Reliable ordered delivery of messages on top of an unreliable transport.
+//!
+//! [Channel] is a state machine for a single peer that doesn't do any IO.
+//! The owner passes into it the frames received from the peer
+//! and sends to the peer the frames it produces. So, the same code works
+//! over UDP sockets as well as over an in-memory loopback in tests.
+use crate::shared::{Duration, Instant};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+/// The size of an encoded [Frame] without the payload.
+pub const HEADER_SIZE: usize = 9;
+
+/// How many messages can be in flight without being acknowledged.
+const WINDOW: u32 = 16;
+
+/// How long to wait for an acknowledgement before sending the message again.
+const RETRY_TIMEOUT: Duration = Duration::from_ms(100);
+
+/// How many times to send a message before considering the peer gone.
+const MAX_ATTEMPTS: u8 = 20;
+
+const KIND_DATA: u8 = 0;
+const KIND_ACK: u8 = 1;
+
+/// A single datagram exchanged between two channels.
+///
+/// The session is an ID picked by the sending side when the channel
+/// is created. It lets the peer detect that the channel was restarted
+/// and the sequence numbers start over.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Frame<'a> {
+    /// A message with its sequence number.
+    Data {
+        session: u32,
+        seq: u32,
+        data: &'a [u8],
+    },
+    /// Acknowledgement of all messages with sequence numbers before the given one.
+    Ack { session: u32, seq: u32 },
+}
+
+impl<'a> Frame<'a> {
+    /// Parse the frame. Returns None if the frame is malformed.
+    pub fn decode(raw: &'a [u8]) -> Option<Self> {
+        if raw.len() < HEADER_SIZE {
+            return None;
+        }
+        let session = u32::from_le_bytes([raw[1], raw[2], raw[3], raw[4]]);
+        let seq = u32::from_le_bytes([raw[5], raw[6], raw[7], raw[8]]);
+        match raw[0] {
+            KIND_DATA => Some(Self::Data {
+                session,
+                seq,
+                data: &raw[HEADER_SIZE..],
+            }),
+            KIND_ACK if raw.len() == HEADER_SIZE => Some(Self::Ack { session, seq }),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, session, seq, data): (u8, u32, u32, &[u8]) = match *self {
+            Self::Data { session, seq, data } => (KIND_DATA, session, seq, data),
+            Self::Ack { session, seq } => (KIND_ACK, session, seq, &[]),
+        };
+        let mut raw = Vec::with_capacity(HEADER_SIZE + data.len());
+        raw.push(kind);
+        raw.extend_from_slice(&session.to_le_bytes());
+        raw.extend_from_slice(&seq.to_le_bytes());
+        raw.extend_from_slice(data);
+        raw
+    }
+}
+
+/// A message waiting for acknowledgement.
+struct Pending {
+    seq: u32,
+    data: Box<[u8]>,
+    attempts: u8,
+    sent_at: Option<Instant>,
+}
+
+/// Reliable ordered channel to a single peer.
+pub struct Channel {
+    /// The session ID of the outgoing messages.
+    session: u32,
+    /// The sequence number of the next message to send.
+    next_seq: u32,
+    /// Sent messages that are not acknowledged yet, ordered by sequence number.
+    pending: VecDeque<Pending>,
+    /// The session ID of the incoming messages.
+    peer_session: Option<u32>,
+    /// The sequence number of the next message to deliver.
+    expected: u32,
+    /// Messages that arrived ahead of a message that was lost.
+    early: BTreeMap<u32, Box<[u8]>>,
+    /// Messages ready to be delivered, in order.
+    ready: VecDeque<Box<[u8]>>,
+    broken: bool,
+}
+
+impl Channel {
+    /// Create a new channel.
+    ///
+    /// The session ID must be greater (accounting for wrapping) than the ID of
+    /// any previous channel to the same peer, for example, derived from the clock.
+    /// The peer switches to a new session only if it is newer than the current one,
+    /// so that a stale frame from a previous channel can't reset the state.
+    pub fn new(session: u32) -> Self {
+        Self {
+            session,
+            next_seq: 0,
+            pending: VecDeque::new(),
+            peer_session: None,
+            expected: 0,
+            early: BTreeMap::new(),
+            ready: VecDeque::new(),
+            broken: false,
+        }
+    }
+
+    /// Queue the message for sending. It will be sent by the next [Channel::poll].
+    pub fn send(&mut self, data: Box<[u8]>) {
+        self.pending.push_back(Pending {
+            seq: self.next_seq,
+            data,
+            attempts: 0,
+            sent_at: None,
+        });
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+
+    /// Process a frame received from the peer.
+    ///
+    /// Returns the acknowledgement that must be sent back to the peer, if any.
+    pub fn handle(&mut self, frame: Frame<'_>) -> Option<Frame<'static>> {
+        match frame {
+            Frame::Data { session, seq, data } => {
+                if let Some(peer_session) = self.peer_session
+                    && is_before(session, peer_session)
+                {
+                    return None;
+                }
+                if self.peer_session != Some(session) {
+                    self.peer_session = Some(session);
+                    self.expected = 0;
+                    self.early.clear();
+                }
+                // Duplicates of already delivered messages are out of the window
+                // because of the wrapping subtraction. They are dropped
+                // but still acknowledged in case the previous ack was lost.
+                if seq.wrapping_sub(self.expected) < WINDOW {
+                    self.early.entry(seq).or_insert_with(|| data.into());
+                }
+                while let Some(data) = self.early.remove(&self.expected) {
+                    self.ready.push_back(data);
+                    self.expected = self.expected.wrapping_add(1);
+                }
+                Some(Frame::Ack {
+                    session,
+                    seq: self.expected,
+                })
+            }
+            Frame::Ack { session, seq } => {
+                if session != self.session {
+                    return None;
+                }
+                while let Some(pending) = self.pending.front()
+                    && is_before(pending.seq, seq)
+                {
+                    self.pending.pop_front();
+                }
+                None
+            }
+        }
+    }
+
+    /// Get the next received message, in the order they were sent.
+    pub fn recv(&mut self) -> Option<Box<[u8]>> {
+        self.ready.pop_front()
+    }
+
+    /// Send the messages that are due for (re)transmission.
+    ///
+    /// Must be called regularly. If the peer doesn't acknowledge a message
+    /// after many attempts, the channel is marked as broken and stops sending.
+    pub fn poll<F: FnMut(Frame<'_>)>(&mut self, now: Instant, mut send: F) {
+        if self.broken {
+            return;
+        }
+        for pending in self.pending.iter_mut().take(WINDOW as usize) {
+            if let Some(sent_at) = pending.sent_at
+                && now - sent_at < RETRY_TIMEOUT
+            {
+                continue;
+            }
+            if pending.attempts >= MAX_ATTEMPTS {
+                self.broken = true;
+                return;
+            }
+            pending.attempts += 1;
+            pending.sent_at = Some(now);
+            send(Frame::Data {
+                session: self.session,
+                seq: pending.seq,
+                data: &pending.data,
+            });
+        }
+    }
+
+    /// When [Channel::poll] has to be called next. None if there is nothing to send.
+    pub fn next_poll(&self) -> Option<Instant> {
+        if self.broken {
+            return None;
+        }
+        let window = self.pending.iter().take(WINDOW as usize);
+        let times = window.map(|pending| match pending.sent_at {
+            Some(sent_at) => sent_at + RETRY_TIMEOUT,
+            None => Instant { us: 0 },
+        });
+        times.min()
+    }
+
+    /// True if the peer stopped acknowledging messages.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Drop all unacknowledged messages and start sending in a new session.
+    ///
+    /// Used to recover a broken channel. The session ID has the same requirements
+    /// as for [Channel::new]. The state of the incoming messages is kept,
+    /// so stale frames from the previous sessions of the peer are still ignored.
+    pub fn restart(&mut self, session: u32) {
+        self.session = session;
+        self.next_seq = 0;
+        self.pending.clear();
+        self.broken = false;
+    }
+}
+
+/// Compare sequence numbers accounting for wrapping.
+fn is_before(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two channels connected by an in-memory link with simulated bad network conditions.
+    struct Loopback {
+        a: Channel,
+        b: Channel,
+        now: Instant,
+        /// Frames in flight from A to B.
+        to_b: Vec<Vec<u8>>,
+        /// Frames in flight from B to A.
+        to_a: Vec<Vec<u8>>,
+        /// The probability (in percents) that a frame is lost.
+        loss: u32,
+        /// The probability (in percents) that a frame is sent twice.
+        duplicate: u32,
+        /// If true, the frames in flight are delivered in a random order.
+        reorder: bool,
+        /// The state of the pseudo-random number generator.
+        rng: u32,
+    }
+
+    impl Loopback {
+        fn new() -> Self {
+            Self {
+                a: Channel::new(1),
+                b: Channel::new(1),
+                now: Instant { us: 0 },
+                to_b: Vec::new(),
+                to_a: Vec::new(),
+                loss: 0,
+                duplicate: 0,
+                reorder: false,
+                rng: 42,
+            }
+        }
+
+        /// Xorshift, so that the tests are deterministic.
+        fn random(&mut self, max: u32) -> u32 {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 17;
+            self.rng ^= self.rng << 5;
+            self.rng % max
+        }
+
+        /// Put the frame on the wire, maybe losing or duplicating it.
+        fn transmit(&mut self, to_b: bool, frame: Frame<'_>) {
+            if self.random(100) < self.loss {
+                return;
+            }
+            let copies = if self.random(100) < self.duplicate {
+                2
+            } else {
+                1
+            };
+            for _ in 0..copies {
+                let queue = if to_b { &mut self.to_b } else { &mut self.to_a };
+                queue.push(frame.encode());
+            }
+        }
+
+        /// Send all due frames, deliver all frames in flight, and advance the time.
+        fn step(&mut self) {
+            let mut frames = Vec::new();
+            self.a.poll(self.now, |frame| frames.push(frame.encode()));
+            for raw in frames.drain(..) {
+                self.transmit(true, Frame::decode(&raw).unwrap());
+            }
+            self.b.poll(self.now, |frame| frames.push(frame.encode()));
+            for raw in frames.drain(..) {
+                self.transmit(false, Frame::decode(&raw).unwrap());
+            }
+
+            let mut to_b = core::mem::take(&mut self.to_b);
+            let mut to_a = core::mem::take(&mut self.to_a);
+            if self.reorder {
+                self.shuffle(&mut to_b);
+                self.shuffle(&mut to_a);
+            }
+            for raw in to_b {
+                if let Some(ack) = self.b.handle(Frame::decode(&raw).unwrap()) {
+                    self.transmit(false, ack);
+                }
+            }
+            for raw in to_a {
+                self.a.handle(Frame::decode(&raw).unwrap());
+            }
+            self.now += Duration::from_ms(10);
+        }
+
+        fn shuffle(&mut self, frames: &mut [Vec<u8>]) {
+            for i in (1..frames.len()).rev() {
+                let j = self.random(i as u32 + 1) as usize;
+                frames.swap(i, j);
+            }
+        }
+
+        /// Send the messages from A to B and return everything B received.
+        fn run(&mut self, count: u8) -> Vec<u8> {
+            for i in 0..count {
+                self.a.send(Box::new([i]));
+            }
+            let mut received = Vec::new();
+            for _ in 0..1000 {
+                self.step();
+                while let Some(data) = self.b.recv() {
+                    received.push(data[0]);
+                }
+                if self.a.next_poll().is_none() {
+                    break;
+                }
+            }
+            received
+        }
+    }
+
+    fn expected(count: u8) -> Vec<u8> {
+        (0..count).collect()
+    }
+
+    #[test]
+    fn test_frame_encode_decode() {
+        let frame = Frame::Data {
+            session: 3,
+            seq: 7,
+            data: b"hello",
+        };
+        let raw = frame.encode();
+        assert_eq!(raw.len(), HEADER_SIZE + 5);
+        assert_eq!(Frame::decode(&raw), Some(frame));
+        let frame = Frame::Ack { session: 3, seq: 8 };
+        assert_eq!(Frame::decode(&frame.encode()), Some(frame));
+        assert_eq!(Frame::decode(&raw[..HEADER_SIZE - 1]), None);
+        assert_eq!(Frame::decode(&[9; HEADER_SIZE]), None);
+    }
+
+    #[test]
+    fn test_ideal() {
+        let mut lo = Loopback::new();
+        assert_eq!(lo.run(50), expected(50));
+        assert!(!lo.a.is_broken());
+    }
+
+    #[test]
+    fn test_loss() {
+        let mut lo = Loopback::new();
+        lo.loss = 30;
+        assert_eq!(lo.run(50), expected(50));
+        assert!(!lo.a.is_broken());
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut lo = Loopback::new();
+        lo.reorder = true;
+        assert_eq!(lo.run(50), expected(50));
+    }
+
+    #[test]
+    fn test_duplicate() {
+        let mut lo = Loopback::new();
+        lo.duplicate = 50;
+        assert_eq!(lo.run(50), expected(50));
+    }
+
+    #[test]
+    fn test_all_conditions() {
+        let mut lo = Loopback::new();
+        lo.loss = 20;
+        lo.duplicate = 20;
+        lo.reorder = true;
+        assert_eq!(lo.run(100), expected(100));
+    }
+
+    #[test]
+    fn test_session_restart() {
+        let mut lo = Loopback::new();
+        assert_eq!(lo.run(5), expected(5));
+
+        // The sender restarts and the sequence numbers start over.
+        lo.a = Channel::new(2);
+        assert_eq!(lo.run(3), expected(3));
+
+        // A frame from the previous session arriving late is ignored
+        // and doesn't reset the receiver.
+        let stale = Frame::Data {
+            session: 1,
+            seq: 0,
+            data: &[99],
+        };
+        assert_eq!(lo.b.handle(stale), None);
+        assert_eq!(lo.b.recv(), None);
+        let frame = Frame::Data {
+            session: 2,
+            seq: 3,
+            data: &[3],
+        };
+        let ack = lo.b.handle(frame);
+        assert_eq!(ack, Some(Frame::Ack { session: 2, seq: 4 }));
+        assert_eq!(lo.b.recv().as_deref(), Some(&[3][..]));
+    }
+
+    #[test]
+    fn test_broken() {
+        let mut lo = Loopback::new();
+        lo.loss = 100;
+        assert_eq!(lo.run(1), Vec::<u8>::new());
+        assert!(lo.a.is_broken());
+        assert_eq!(lo.a.next_poll(), None);
+
+        // After the restart, the peer accepts the new session.
+        lo.loss = 0;
+        lo.a.restart(2);
+        assert!(!lo.a.is_broken());
+        assert_eq!(lo.run(3), expected(3));
+    }
+}
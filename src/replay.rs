@@ -0,0 +1,304 @@
+//! Recording and replaying of all non-deterministic device inputs.
+//!
+//! The recording is a sequence of postcard-encoded [Event]s,
+//! each one framed with COBS and terminated by a zero byte.
+//! So, a recording that was cut short by a crash is still readable
+//! up to the last complete event.
+use crate::shared::*;
+use alloc::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize)]
+enum Event {
+    /// The value returned by [Device::now].
+    Now(u64),
+    /// The value returned by [Device::read_input].
+    Input(Option<RecordedInput>),
+    /// The value returned by [Device::random].
+    Random(u32),
+    /// The value returned by [Device::now_utc], in seconds since the Unix epoch.
+    NowUtc(Option<u64>),
+    /// The samples returned by [Device::read_audio].
+    Audio(Vec<i16>),
+}
+
+/// Serializable copy of [InputState].
+#[derive(Serialize, Deserialize)]
+struct RecordedInput {
+    pad: Option<(i16, i16)>,
+    buttons: u8,
+}
+
+impl From<&InputState> for RecordedInput {
+    fn from(input: &InputState) -> Self {
+        Self {
+            pad: input.pad.clone().map(Into::into),
+            buttons: input.buttons,
+        }
+    }
+}
+
+impl From<RecordedInput> for InputState {
+    fn from(input: RecordedInput) -> Self {
+        Self {
+            pad: input.pad.map(Into::into),
+            buttons: input.buttons,
+        }
+    }
+}
+
+/// The source of non-deterministic inputs for the device.
+pub(crate) enum Session {
+    /// Use live values.
+    Live,
+    /// Use live values and write them into a file.
+    Recording(Box<dyn Write + Send>),
+    /// Use values from a previously recorded file.
+    Replaying(Replay),
+}
+
+impl Session {
+    /// Start recording into the given file.
+    ///
+    /// If the file cannot be created, a warning is printed
+    /// and the session falls back to live values.
+    pub fn record(path: &Path) -> Self {
+        match std::fs::File::create(path) {
+            Ok(file) => Self::Recording(Box::new(std::io::BufWriter::new(file))),
+            Err(err) => {
+                eprintln!("WARNING: cannot create recording file: {err}");
+                Self::Live
+            }
+        }
+    }
+
+    /// Start replaying the given file.
+    ///
+    /// If the file cannot be read, a warning is printed
+    /// and the session falls back to live values.
+    pub fn replay(path: &Path) -> Self {
+        match std::fs::read(path) {
+            Ok(raw) => Self::replay_bytes(&raw),
+            Err(err) => {
+                eprintln!("WARNING: cannot read recording file: {err}");
+                Self::Live
+            }
+        }
+    }
+
+    /// Start replaying the recording loaded into memory.
+    fn replay_bytes(raw: &[u8]) -> Self {
+        let mut replay = Replay {
+            now: VecDeque::new(),
+            input: VecDeque::new(),
+            random: VecDeque::new(),
+            now_utc: VecDeque::new(),
+            audio: VecDeque::new(),
+        };
+        for frame in raw.split_inclusive(|b| *b == 0) {
+            let mut frame = frame.to_vec();
+            let Ok(event) = postcard::from_bytes_cobs(&mut frame) else {
+                eprintln!("WARNING: recording file is truncated or corrupted");
+                break;
+            };
+            match event {
+                Event::Now(us) => replay.now.push_back(us),
+                Event::Input(input) => replay.input.push_back(input.map(Into::into)),
+                Event::Random(val) => replay.random.push_back(val),
+                Event::NowUtc(secs) => replay.now_utc.push_back(secs),
+                Event::Audio(samples) => replay.audio.push_back(samples),
+            }
+        }
+        Self::Replaying(replay)
+    }
+
+    pub fn now<F: FnOnce() -> u64>(&mut self, live: F) -> u64 {
+        if let Self::Replaying(replay) = self
+            && let Some(us) = replay.now.pop_front()
+        {
+            return us;
+        }
+        let us = live();
+        self.write(&Event::Now(us));
+        // Flush once per frame to not lose the recording on crash.
+        if let Self::Recording(file) = self {
+            _ = file.flush();
+        }
+        us
+    }
+
+    pub fn read_input<F>(&mut self, live: F) -> Option<InputState>
+    where
+        F: FnOnce() -> Option<InputState>,
+    {
+        if let Self::Replaying(replay) = self
+            && let Some(input) = replay.input.pop_front()
+        {
+            return input;
+        }
+        let input = live();
+        self.write(&Event::Input(input.as_ref().map(Into::into)));
+        input
+    }
+
+    pub fn random<F: FnOnce() -> u32>(&mut self, live: F) -> u32 {
+        if let Self::Replaying(replay) = self
+            && let Some(val) = replay.random.pop_front()
+        {
+            return val;
+        }
+        let val = live();
+        self.write(&Event::Random(val));
+        val
+    }
+
+    pub fn now_utc<F: FnOnce() -> Option<u64>>(&mut self, live: F) -> Option<u64> {
+        if let Self::Replaying(replay) = self
+            && let Some(secs) = replay.now_utc.pop_front()
+        {
+            return secs;
+        }
+        let secs = live();
+        self.write(&Event::NowUtc(secs));
+        secs
+    }
+
+    /// Replace the content of the buffer with the audio input samples.
+    pub fn read_audio<F>(&mut self, buf: &mut Vec<i16>, live: F)
+    where
+        F: FnOnce(&mut Vec<i16>),
+    {
+        buf.clear();
+        if let Self::Replaying(replay) = self
+            && let Some(samples) = replay.audio.pop_front()
+        {
+            *buf = samples;
+            return;
+        }
+        live(buf);
+        if let Self::Recording(_) = self {
+            self.write(&Event::Audio(buf.clone()));
+        }
+    }
+
+    fn write(&mut self, event: &Event) {
+        let Self::Recording(file) = self else {
+            return;
+        };
+        let res = postcard::to_allocvec_cobs(event);
+        let Ok(raw) = res else {
+            return;
+        };
+        if let Err(err) = file.write_all(&raw) {
+            eprintln!("WARNING: cannot write recording, recording stopped: {err}");
+            *self = Self::Live;
+        }
+    }
+}
+
+/// Values loaded from a recording, grouped by the kind of event.
+///
+/// When a queue is exhausted, the live values are used instead.
+pub(crate) struct Replay {
+    now: VecDeque<u64>,
+    input: VecDeque<Option<InputState>>,
+    random: VecDeque<u32>,
+    now_utc: VecDeque<Option<u64>>,
+    audio: VecDeque<Vec<i16>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer into memory that stays readable after the session takes it.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Record a session, producing the same events as [replay_all] checks.
+    fn record_all() -> Vec<u8> {
+        let buf = SharedBuf::default();
+        let mut session = Session::Recording(Box::new(buf.clone()));
+        assert_eq!(session.now(|| 100), 100);
+        let input = InputState {
+            pad: Some(Pad { x: -30, y: 40 }),
+            buttons: 5,
+        };
+        session.read_input(|| Some(input));
+        assert_eq!(session.random(|| 42), 42);
+        assert_eq!(session.now_utc(|| Some(1_700_000_000)), Some(1_700_000_000));
+        let mut audio = Vec::new();
+        session.read_audio(&mut audio, |buf| buf.extend([1, -2, 3]));
+        assert_eq!(audio, [1, -2, 3]);
+        session.now(|| 200);
+        session.read_input(|| None);
+        session.now_utc(|| None);
+        drop(session);
+        buf.0.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let raw = record_all();
+        let mut session = Session::replay_bytes(&raw);
+        assert_eq!(session.now(|| unreachable!()), 100);
+        let input = session.read_input(|| unreachable!()).unwrap();
+        let pad = input.pad.unwrap();
+        assert_eq!((pad.x, pad.y, input.buttons), (-30, 40, 5));
+        assert_eq!(session.random(|| unreachable!()), 42);
+        assert_eq!(session.now_utc(|| unreachable!()), Some(1_700_000_000));
+        let mut audio = Vec::new();
+        session.read_audio(&mut audio, |_| unreachable!());
+        assert_eq!(audio, [1, -2, 3]);
+        assert_eq!(session.now(|| unreachable!()), 200);
+        assert!(session.read_input(|| unreachable!()).is_none());
+        assert_eq!(session.now_utc(|| unreachable!()), None);
+
+        // When the recording is over, live values are used.
+        assert_eq!(session.now(|| 300), 300);
+        assert_eq!(session.random(|| 7), 7);
+        session.read_audio(&mut audio, |buf| buf.push(9));
+        assert_eq!(audio, [9]);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut raw = record_all();
+        // Cut the last event (NowUtc) in the middle.
+        raw.truncate(raw.len() - 2);
+        let mut session = Session::replay_bytes(&raw);
+        assert_eq!(session.now(|| unreachable!()), 100);
+        assert_eq!(session.now(|| unreachable!()), 200);
+        assert_eq!(session.now_utc(|| unreachable!()), Some(1_700_000_000));
+        // The truncated event is lost, everything before it is replayed.
+        assert_eq!(session.now_utc(|| Some(5)), Some(5));
+        assert!(session.read_input(|| unreachable!()).is_some());
+        assert!(session.read_input(|| unreachable!()).is_none());
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let mut raw = record_all();
+        // Corrupt the second event (Input) and everything after it is ignored.
+        let second = raw.iter().position(|b| *b == 0).unwrap() + 2;
+        raw[second] = 0;
+        let mut session = Session::replay_bytes(&raw);
+        assert_eq!(session.now(|| unreachable!()), 100);
+        assert_eq!(session.now(|| 1), 1);
+        assert_eq!(session.random(|| 2), 2);
+    }
+}
@@ -1,18 +1,51 @@
 use crate::errors::*;
 use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt::Display;
+use core::net::IpAddr;
+use core::ops::Add;
 use core::ops::AddAssign;
 use core::ops::Sub;
 use core::ops::SubAssign;
 use firefly_types::spi::SendStatus;
+use serde::{Deserialize, Serialize};
 
 pub const SAMPLE_RATE: u32 = 44_100;
 
+/// The default value for [Network::net_max_message_size].
+pub const MAX_MESSAGE_SIZE: usize = 200;
+
+/// The name of the temporary file used by [Dir::replace_file].
+///
+/// Must be a valid FAT 8.3 short name.
+pub(crate) const TMP_FILE_NAME: &str = "_REPLACE.TMP";
+
 /// A moment in time. Obtained from [Device::now].
-#[derive(Copy, Clone)]
+///
+/// Stores microseconds since an unspecified starting point (usually, the device boot).
+/// 64 bits of microseconds are enough for half a million years of uptime,
+/// so it never wraps around in practice.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Debug)]
 pub struct Instant {
-    pub us: u32,
+    pub us: u64,
+}
+
+impl Instant {
+    /// The time passed since this moment.
+    pub fn elapsed<D: Device>(&self, device: &D) -> Duration {
+        device.now() - *self
+    }
+
+    /// The time passed from the earlier moment to this one.
+    ///
+    /// Returns None if the given moment is later than this one.
+    pub const fn checked_sub(&self, earlier: Self) -> Option<Duration> {
+        match self.us.checked_sub(earlier.us) {
+            Some(us) => Some(Duration { us }),
+            None => None,
+        }
+    }
 }
 
 impl Sub for Instant {
@@ -25,47 +58,101 @@ impl Sub for Instant {
     }
 }
 
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self {
+        Self {
+            us: self.us.saturating_add(rhs.us),
+        }
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        self.us = self.us.saturating_add(rhs.us)
+    }
+}
+
 /// Difference between two [Instant]'s. Used by [Device::delay].
-#[derive(PartialEq, PartialOrd, Copy, Clone)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Debug, Default)]
 pub struct Duration {
-    pub(crate) us: u32,
+    pub(crate) us: u64,
 }
 
 impl Duration {
     /// Given the desired frames per second, get the duration of a single frame.
     pub const fn from_fps(fps: u32) -> Self {
         Self {
-            us: 1_000_000 / fps,
+            us: 1_000_000 / fps as u64,
         }
     }
 
-    pub const fn from_s(s: u32) -> Self {
-        Self { us: s * 1_000_000 }
+    pub const fn from_s(s: u64) -> Self {
+        Self {
+            us: s.saturating_mul(1_000_000),
+        }
     }
 
-    pub const fn from_ms(ms: u32) -> Self {
-        Self { us: ms * 1000 }
+    pub const fn from_ms(ms: u64) -> Self {
+        Self {
+            us: ms.saturating_mul(1000),
+        }
     }
 
-    pub const fn from_us(us: u32) -> Self {
+    pub const fn from_us(us: u64) -> Self {
         Self { us }
     }
 
-    pub const fn s(&self) -> u32 {
+    pub const fn s(&self) -> u64 {
         self.us / 1_000_000
     }
 
-    pub const fn ms(&self) -> u32 {
+    pub const fn ms(&self) -> u64 {
         self.us / 1000
     }
 
-    pub const fn us(&self) -> u32 {
+    pub const fn us(&self) -> u64 {
         self.us
     }
 
-    pub const fn ns(&self) -> u32 {
+    pub const fn ns(&self) -> u64 {
         self.us.saturating_mul(1000)
     }
+
+    /// Subtract the given duration, returning None on underflow.
+    pub const fn checked_sub(&self, rhs: Self) -> Option<Self> {
+        match self.us.checked_sub(rhs.us) {
+            Some(us) => Some(Self { us }),
+            None => None,
+        }
+    }
+}
+
+impl From<Duration> for core::time::Duration {
+    fn from(value: Duration) -> Self {
+        Self::from_micros(value.us)
+    }
+}
+
+impl From<core::time::Duration> for Duration {
+    /// Convert from the stdlib duration, truncating to whole microseconds.
+    ///
+    /// Durations that don't fit into 64 bits of microseconds are saturated.
+    fn from(value: core::time::Duration) -> Self {
+        let us = u64::try_from(value.as_micros()).unwrap_or(u64::MAX);
+        Self { us }
+    }
+}
+
+impl Add for Duration {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            us: self.us.saturating_add(rhs.us),
+        }
+    }
 }
 
 impl Sub for Duration {
@@ -113,6 +200,21 @@ pub trait Device: Network + Serial + Wifi {
     /// [embedded_hal.DelayNs]: https://docs.rs/embedded-hal/1.0.0/embedded_hal/delay/trait.DelayNs.html
     fn delay(&self, d: Duration);
 
+    /// The current wall-clock date and time in UTC.
+    ///
+    /// Unlike [Device::now], it's not monotonic and may jump
+    /// when the clock is adjusted by [Device::set_utc].
+    ///
+    /// None if the device doesn't know the current time (yet).
+    fn now_utc(&self) -> Option<DateTime>;
+
+    /// Set the current wall-clock date and time in UTC.
+    ///
+    /// Used by the runtime to sync the device clock with the host.
+    /// Devices that get the time from elsewhere (like the system clock)
+    /// may ignore it. Invalid dates (see [DateTime::is_valid]) are ignored.
+    fn set_utc(&mut self, now: DateTime);
+
     /// Read gamepad input.
     fn read_input(&mut self) -> Option<InputState>;
 
@@ -149,6 +251,13 @@ pub trait Device: Network + Serial + Wifi {
     /// Get a writable slice of free audio buffer region.
     fn get_audio_buffer(&mut self) -> &mut [i16];
 
+    /// Read audio captured from the microphone since the previous call.
+    ///
+    /// The audio is mono PCM at [SAMPLE_RATE]. If there is no microphone,
+    /// the returned slice is empty. The first call may start the capture,
+    /// so it's likely to return nothing.
+    fn read_audio(&mut self) -> &[i16];
+
     fn get_battery_status(&mut self) -> Option<BatteryStatus>;
 }
 
@@ -173,15 +282,56 @@ pub trait Network {
     fn net_local_addr(&self) -> Self::Addr;
 
     /// Broadcast device presence to all other devices nearby.
+    ///
+    /// The advertisement carries the data set by [Network::net_set_advertisement].
     fn net_advertise(&mut self) -> NetworkResult<()>;
 
+    /// Set the data to include into all the following advertisements.
+    ///
+    /// Returns [NetworkError::OutMessageTooBig] if the encoded advertisement
+    /// is bigger than [Network::net_max_message_size].
+    fn net_set_advertisement(&mut self, ad: Advertisement) -> NetworkResult<()>;
+
+    /// All peers discovered through their advertisements since [Network::net_start].
+    fn net_peers(&mut self) -> NetworkResult<Vec<Peer<Self::Addr>>>;
+
+    /// Authenticate all the following messages with the given key.
+    ///
+    /// The key must be agreed on with the peers at lobby time.
+    /// Messages that aren't authenticated with the same key are dropped
+    /// before reaching [Network::net_recv]. Advertisements are never authenticated
+    /// because they are needed to find the peers before there is a key.
+    /// So, while the key is set, they still update [Network::net_peers]
+    /// but don't produce any messages. Replayed messages are dropped as well.
+    ///
+    /// The key stays set after [Network::net_stop] and applies again
+    /// after the next [Network::net_start].
+    ///
+    /// Pass None to go back to unauthenticated messages.
+    fn net_set_key(&mut self, key: Option<SessionKey>) -> NetworkResult<()>;
+
     /// Get a pending message, if any. Non-blocking.
     #[expect(clippy::type_complexity)]
     fn net_recv(&mut self) -> NetworkResult<Option<(Self::Addr, Box<[u8]>)>>;
 
     /// Send a raw message to the given device. Non-blocking.
+    ///
+    /// Returns [NetworkError::OutMessageTooBig] if the message is bigger
+    /// than [Network::net_max_message_size].
     fn net_send(&mut self, addr: Self::Addr, data: &[u8]) -> NetworkResult<()>;
 
+    /// Send a message to the given device with guaranteed ordered delivery. Non-blocking.
+    ///
+    /// Unlike [Network::net_send], the message is retransmitted until the peer
+    /// acknowledges it, and the peer receives messages from [Network::net_recv]
+    /// in the same order as they were sent. If the peer stops responding,
+    /// the messages that it hasn't received yet are dropped
+    /// and [Network::net_send_status] for the peer returns [SendStatus::Failed].
+    fn net_send_reliable(&mut self, addr: Self::Addr, data: &[u8]) -> NetworkResult<()>;
+
+    /// The maximum size in bytes of a message that can be sent or received.
+    fn net_max_message_size(&self) -> usize;
+
     /// Send a raw message to the given device. Non-blocking.
     fn net_send_status(&mut self, addr: Self::Addr) -> NetworkResult<SendStatus>;
 }
@@ -191,7 +341,7 @@ pub trait Wifi {
     fn wifi_connect(&mut self, ssid: &str, pass: &str) -> NetworkResult<()>;
     fn wifi_status(&mut self) -> NetworkResult<u8>;
     fn wifi_disconnect(&mut self) -> NetworkResult<()>;
-    fn tcp_connect(&mut self, ip: u32, port: u16) -> NetworkResult<()>;
+    fn tcp_connect(&mut self, ip: IpAddr, port: u16) -> NetworkResult<()>;
     fn tcp_status(&mut self) -> NetworkResult<u8>;
     fn tcp_send(&mut self, data: &[u8]) -> NetworkResult<()>;
     fn tcp_recv(&mut self) -> NetworkResult<Box<[u8]>>;
@@ -201,6 +351,7 @@ pub trait Wifi {
 pub trait Dir {
     type Read: embedded_io::Read + embedded_io::Seek;
     type Write: embedded_io::Write;
+    type ReadWrite: embedded_io::Read + embedded_io::Write + embedded_io::Seek;
 
     /// Open a file for reading.
     ///
@@ -229,16 +380,58 @@ pub trait Dir {
     /// Write data to the end of the file.
     fn append_file(&mut self, name: &str) -> Result<Self::Write, FSError>;
 
+    /// Open an existing file for both reading and writing.
+    ///
+    /// The cursor is placed at the beginning of the file. Writing overwrites
+    /// the existing content at the cursor position without truncating the file,
+    /// so it can be used to update fixed-size records in place.
+    fn open_file_rw(&mut self, name: &str) -> Result<Self::ReadWrite, FSError>;
+
     /// Get file size in bytes.
     ///
     /// None should be returned if file not found.
     fn get_file_size(&mut self, name: &str) -> Result<u32, FSError>;
 
+    /// Get the kind, size, and modification time of the given file or directory.
+    ///
+    /// Doesn't open the file.
+    fn metadata(&mut self, name: &str) -> Result<Metadata, FSError>;
+
     /// Delete the given file if exists.
     ///
     /// Returns false only if there is an error.
     fn remove_file(&mut self, name: &str) -> Result<(), FSError>;
 
+    /// Rename the file, replacing the target file if it exists.
+    fn rename_file(&mut self, from: &str, to: &str) -> Result<(), FSError>;
+
+    /// Replace the file content so that it is never left half-written.
+    ///
+    /// The data is first written into a temporary file which is then
+    /// renamed into the target file. If the write is interrupted (by a crash
+    /// or the battery dying), the old content of the target file stays intact.
+    ///
+    /// The default implementation relies on flushing the file to write
+    /// the data onto the storage, which is true for the SD card.
+    /// Backends with write caches must override it to sync the file before renaming.
+    ///
+    /// Returns [FSError::InvalidInput] for the name of the temporary file.
+    fn replace_file(&mut self, name: &str, data: &[u8]) -> Result<(), FSError> {
+        use embedded_io::{Error, Write};
+        if name == TMP_FILE_NAME {
+            return Err(FSError::InvalidInput);
+        }
+        let mut file = self.create_file(TMP_FILE_NAME)?;
+        let res = file.write_all(data).and_then(|_| file.flush());
+        drop(file);
+        let res = res.map_err(|err| err.kind().into());
+        let res = res.and_then(|_| self.rename_file(TMP_FILE_NAME, name));
+        if res.is_err() {
+            _ = self.remove_file(TMP_FILE_NAME);
+        }
+        res
+    }
+
     /// Create a new empty sub-directory.
     ///
     /// Returns `DirAlreadyExists` if directory already exists.
@@ -256,22 +449,135 @@ pub trait Dir {
     fn iter_dir<F>(&mut self, f: F) -> Result<(), FSError>
     where
         F: FnMut(EntryKind, &[u8]);
+
+    /// Like [Dir::iter_dir] but also provides the size and modification time of each entry.
+    fn iter_dir_meta<F>(&mut self, f: F) -> Result<(), FSError>
+    where
+        F: FnMut(&Metadata, &[u8]);
 }
 
 /// Access the USB serial port.
 pub trait Serial {
     fn serial_start(&mut self) -> NetworkResult<()>;
     fn serial_stop(&mut self) -> NetworkResult<()>;
-    fn serial_recv(&mut self) -> NetworkResult<Option<Box<[u8]>>>;
-    fn serial_send(&mut self, data: &[u8]) -> NetworkResult<()>;
+
+    /// Get the next message from any client.
+    ///
+    /// Connection events and the client IDs are skipped.
+    /// Use [Serial::serial_recv_event] to get them.
+    fn serial_recv(&mut self) -> NetworkResult<Option<Box<[u8]>>> {
+        while let Some(event) = self.serial_recv_event()? {
+            if let SerialEvent::Message(_, data) = event {
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Send the message to all connected clients.
+    fn serial_send(&mut self, data: &[u8]) -> NetworkResult<()> {
+        self.serial_send_to(None, data)
+    }
+
+    /// Get the next message or connection event.
+    fn serial_recv_event(&mut self) -> NetworkResult<Option<SerialEvent>>;
+
+    /// Send the message to the given client or, if None, to all connected clients.
+    ///
+    /// Messages for clients that are already disconnected are dropped.
+    /// Returns [NetworkError::OutMessageTooBig] if the message is bigger
+    /// than [crate::framing::SERIAL_MAX_SIZE].
+    fn serial_send_to(&mut self, client: Option<ClientId>, data: &[u8]) -> NetworkResult<()>;
+
+    /// The number of bytes of sent messages that are still waiting to be written.
+    fn serial_queued(&self) -> usize;
+
+    /// How many sent messages were dropped because the outgoing queue was full.
+    ///
+    /// Messages are dropped as a whole, the client never gets a truncated message.
+    /// A message sent to all clients is counted once if any of them didn't get it.
+    fn serial_dropped(&self) -> usize;
 }
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum EntryKind {
     Dir,
     File,
 }
 
+/// Device presence information sent by [Network::net_advertise].
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Advertisement {
+    /// The name of the device.
+    pub name: String,
+
+    /// The version of the netplay protocol.
+    ///
+    /// Devices with different protocol versions cannot play together.
+    pub version: u16,
+
+    /// The full ID (author ID and app ID joined by a dot) of the running app.
+    ///
+    /// Empty if no app is running.
+    pub app: String,
+
+    /// How many more players can join the device.
+    pub free_slots: u8,
+}
+
+/// The key for authenticating netplay messages. See [Network::net_set_key].
+#[derive(Clone)]
+pub struct SessionKey {
+    pub key: [u8; 32],
+
+    /// If true, messages are also encrypted. Otherwise, only authenticated.
+    pub encrypt: bool,
+}
+
+/// The ID of a client connected to the serial port.
+///
+/// On the device, there is only one client (the USB host) and its ID is always 0.
+/// The emulator accepts multiple clients and assigns a new ID to each connection.
+pub type ClientId = u32;
+
+/// Something that happened on the serial port. Obtained from [Serial::serial_recv_event].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum SerialEvent {
+    /// A new client has connected.
+    Connected(ClientId),
+    /// The client has disconnected.
+    Disconnected(ClientId),
+    /// A message received from the client.
+    Message(ClientId, Box<[u8]>),
+}
+
+/// A device discovered by its advertisement. Obtained from [Network::net_peers].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Peer<A> {
+    pub addr: A,
+
+    /// The latest advertisement received from the peer.
+    pub advertisement: Advertisement,
+
+    /// When the latest advertisement from the peer was received.
+    pub last_seen: Instant,
+}
+
+/// Information about a file or a directory. Obtained from [Dir::metadata].
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Metadata {
+    pub kind: EntryKind,
+
+    /// The file size in bytes. Always zero for directories.
+    pub size: u32,
+
+    /// The time when the file was last modified.
+    ///
+    /// None if the filesystem doesn't track modification time
+    /// or the time is not known.
+    pub modified: Option<DateTime>,
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct Pad {
     pub x: i16,
@@ -345,6 +651,89 @@ impl InputState {
     }
 }
 
+/// Calendar date and time in UTC.
+///
+/// All fields are one-indexed like on a real calendar,
+/// except the time fields which start from zero.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    /// Month from 1 (January) to 12 (December).
+    pub month: u8,
+    /// Day of month from 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Convert seconds since the Unix epoch (1970-01-01) into a calendar date.
+    ///
+    /// Based on the `civil_from_days` algorithm by Howard Hinnant.
+    pub const fn from_unix(secs: u64) -> Self {
+        let days = secs / 86_400;
+        let rem = secs % 86_400;
+        // Shift the epoch to 0000-03-01 so that the leap day is the last day of year.
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// Check that all fields are in their valid ranges.
+    ///
+    /// The number of days in the month isn't checked, so February 31
+    /// is valid and is the same as March 3 (or 2, in a leap year).
+    pub const fn is_valid(&self) -> bool {
+        self.year > 0
+            && self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= 31
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Convert the calendar date into seconds since the Unix epoch (1970-01-01).
+    ///
+    /// Dates before the epoch are clamped to the epoch.
+    /// Returns None if the date is not valid (see [DateTime::is_valid]).
+    /// Based on the `days_from_civil` algorithm by Howard Hinnant.
+    pub const fn to_unix(&self) -> Option<u64> {
+        if !self.is_valid() {
+            return None;
+        }
+        let month = self.month as u64;
+        let year = self.year as u64 - if month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let yoe = year - era * 400;
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + self.day as u64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe;
+        let Some(days) = days.checked_sub(719_468) else {
+            return Some(0);
+        };
+        let secs = self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+        Some(days * 86_400 + secs)
+    }
+}
+
 /// The battery status info.
 ///
 /// Contains only stats that can be accessed from the hardware.
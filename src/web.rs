@@ -43,7 +43,7 @@ impl Device for DeviceImpl {
 
     fn now(&self) -> Instant {
         Instant {
-            ns: self.perf.now() as u32 * 1_000_000,
+            us: (self.perf.now() * 1000.) as u64,
         }
     }
 
@@ -82,6 +82,12 @@ impl Device for DeviceImpl {
         Some(meta.len as u32)
     }
 
+    fn metadata(&self, path: &[&str]) -> Option<Metadata> {
+        let path = path.join("/");
+        let meta = self.vfs.metadata(&format!("/{path}")).ok()?;
+        Some(convert_metadata(&meta))
+    }
+
     fn make_dir(&self, path: &[&str]) -> bool {
         let path = path.join("/");
         self.vfs.create_dir(&format!("/{path}")).is_ok()
@@ -92,6 +98,14 @@ impl Device for DeviceImpl {
         self.vfs.remove_file(&format!("/{path}")).is_ok()
     }
 
+    fn rename_file(&self, from: &[&str], to: &[&str]) -> bool {
+        let from = from.join("/");
+        let to = to.join("/");
+        self.vfs
+            .move_file(&format!("/{from}"), &format!("/{to}"))
+            .is_ok()
+    }
+
     fn iter_dir<F>(&self, path: &[&str], mut f: F) -> bool
     where
         F: FnMut(EntryKind, &[u8]),
@@ -112,6 +126,38 @@ impl Device for DeviceImpl {
         }
         true
     }
+
+    fn iter_dir_meta<F>(&self, path: &[&str], mut f: F) -> bool
+    where
+        F: FnMut(&Metadata, &[u8]),
+    {
+        let root = path.join("/");
+        let Ok(entries) = self.vfs.read_dir(&format!("/{root}")) else {
+            return false;
+        };
+        for name in entries {
+            let Ok(meta) = self.vfs.metadata(&format!("/{root}/{name}")) else {
+                return false;
+            };
+            f(&convert_metadata(&meta), name.as_bytes());
+        }
+        true
+    }
+}
+
+fn convert_metadata(meta: &vfs::VfsMetadata) -> Metadata {
+    let kind = match meta.file_type {
+        vfs::VfsFileType::File => EntryKind::File,
+        vfs::VfsFileType::Directory => EntryKind::Dir,
+    };
+    let modified = meta
+        .modified
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+    Metadata {
+        kind,
+        size: meta.len as u32,
+        modified: modified.map(|d| DateTime::from_unix(d.as_secs())),
+    }
 }
 
 pub struct FileR {